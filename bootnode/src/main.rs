@@ -0,0 +1,117 @@
+use std::{collections::HashSet, error::Error};
+
+use clap::Parser;
+use env_logger::Env;
+use futures::StreamExt;
+use libp2p::{swarm::SwarmEvent, PeerId};
+use libp2p_connection_limits::{self as connection_limits, ConnectionLimits};
+use libp2p_swarm_derive::NetworkBehaviour;
+use log::{debug, info};
+use networking::{
+    behaviour::{base::BaseBehaviour, wrapped::Wrapped},
+    builder::P2PTransportBuilder,
+    cli::TransportArgs,
+    config::NodeConfig,
+    AgentInfo,
+};
+
+#[derive(Parser)]
+#[command(version, author)]
+struct Cli {
+    #[command(flatten)]
+    pub transport: TransportArgs,
+
+    #[arg(
+        long,
+        env,
+        help = "Maximum number of established connections",
+        default_value_t = 1024
+    )]
+    pub max_connections: u32,
+
+    #[arg(
+        long,
+        env,
+        help = "Maximum number of pending incoming connections",
+        default_value_t = 256
+    )]
+    pub max_pending_incoming: u32,
+
+    #[arg(
+        long,
+        env,
+        help = "Maximum number of established connections per peer",
+        default_value_t = 3
+    )]
+    pub max_connections_per_peer: u32,
+}
+
+#[derive(NetworkBehaviour)]
+struct BootBehaviour {
+    base: Wrapped<BaseBehaviour>,
+    limits: connection_limits::Behaviour,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    let mut cli = Cli::parse();
+    let agent_info = networking::get_agent_info!();
+
+    let limits = ConnectionLimits::default()
+        .with_max_established(Some(cli.max_connections))
+        .with_max_pending_incoming(Some(cli.max_pending_incoming))
+        .with_max_established_per_peer(Some(cli.max_connections_per_peer));
+
+    // Through anyhow, so that all problems are listed readably on exit
+    cli.transport.relay_server = true;
+    let mut config = NodeConfig::load(&cli.transport).map_err(anyhow::Error::from)?;
+    config.base.kad_server_mode = true;
+    if cli.transport.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
+    // The key is generated on first start and reused afterwards, so the
+    // boot node keeps the peer ID other nodes are configured with.
+    let builder = P2PTransportBuilder::from_config(config, agent_info).await?;
+    let mut swarm = builder.build_swarm(|base| BootBehaviour {
+        base: base.into(),
+        limits: connection_limits::Behaviour::new(limits),
+    })?;
+
+    let mut connected: HashSet<PeerId> = HashSet::new();
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } => info!("Listening on {address}"),
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => {
+                if num_established.get() == 1 {
+                    connected.insert(peer_id);
+                    info!(
+                        "Peer {peer_id} connected at {}, {} peers connected",
+                        endpoint.get_remote_address(),
+                        connected.len()
+                    );
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                connected.remove(&peer_id);
+                info!(
+                    "Peer {peer_id} disconnected, {} peers connected",
+                    connected.len()
+                );
+            }
+            other => debug!("Other swarm event: {other:?}"),
+        }
+    }
+}
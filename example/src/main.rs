@@ -1,4 +1,4 @@
-use clap::{command, Parser};
+use clap::Parser;
 use codec::{Decode, Encode};
 use env_logger::Env;
 use futures::StreamExt;
@@ -11,6 +11,7 @@ use networking::{
     },
     builder::P2PTransportBuilder,
     cli::TransportArgs,
+    config::NodeConfig,
     protocol::BLOCKS_TOPIC,
     AgentInfo,
 };
@@ -64,8 +65,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
     let agent_info = networking::get_agent_info!();
 
-    // Build the transport builder from CLI arguments.
-    let builder = P2PTransportBuilder::from_cli(cli.transport, agent_info).await?;
+    // Merge the config file, environment and CLI arguments. Errors go through
+    // anyhow, so that all problems are listed readably on exit.
+    let config = NodeConfig::load(&cli.transport).map_err(anyhow::Error::from)?;
+    if cli.transport.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
+    // Build the transport builder from the merged config.
+    let builder = P2PTransportBuilder::from_config(config, agent_info).await?;
 
     // Create a default Swarm.
     let mut swarm = builder.build_default_swarm()?;
@@ -109,13 +118,11 @@ pub async fn start_block_producer(
                 match event {
                     SwarmEvent::Behaviour(BaseBehaviourEvent::Gossipsub(msg)) => {
                         let message = Message::decode(&mut &msg.message[..]).unwrap();
-                        match message {
-                            Message::Vote(vote) => {
-                              info!("--------------------------------------------");
-                              info!("Message from Peer: {:?}", msg.peer_id);
+                        if let Message::Vote(vote) = message {
+                            info!("--------------------------------------------");
+                            info!("Message from Peer: {:?}", msg.peer_id);
 
-                              info!("Received vote: {:?}", vote)},
-                            _ => {},
+                            info!("Received vote: {:?}", vote)
                         }
                     },
                     other => {
@@ -140,22 +147,19 @@ pub async fn start_voter(
                 match event {
                     SwarmEvent::Behaviour(BaseBehaviourEvent::Gossipsub(msg)) => {
                         let message = Message::decode(&mut &msg.message[..]).unwrap();
-                        match message {
-                            Message::BlockProposal(block) => {
-                              info!("--------------------------------------------");
-                              info!("Message from Peer: {:?}", msg.peer_id);
-
-                              info!("Received block: {:?}", block);
-                              // Create a vote for the received block.
-                              let vote = Vote {
-                                    voter: name.to_string(),
-                                    block: block,
-                              };
-
-                              info!("Publishing vote: {:?}", vote);
-                              swarm.behaviour_mut().publish_message(BLOCKS_TOPIC, Message::Vote(vote));
-                            },
-                            _ => {},
+                        if let Message::BlockProposal(block) = message {
+                            info!("--------------------------------------------");
+                            info!("Message from Peer: {:?}", msg.peer_id);
+
+                            info!("Received block: {:?}", block);
+                            // Create a vote for the received block.
+                            let vote = Vote {
+                                voter: name.to_string(),
+                                block,
+                            };
+
+                            info!("Publishing vote: {:?}", vote);
+                            swarm.behaviour_mut().publish_message(BLOCKS_TOPIC, Message::Vote(vote));
                         }
                     },
                     other => {
@@ -1,36 +1,322 @@
 use std::{
-    collections::HashSet,
-    iter,
+    collections::HashMap,
+    fs, io, iter,
     num::NonZeroUsize,
+    path::{Path, PathBuf},
     task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use codec::{Decode, Encode};
+
 use libp2p::{
     core::{transport::PortUse, Endpoint},
+    multiaddr::Protocol,
     swarm::{
-        dummy::ConnectionHandler, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour,
-        THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+        dummy::ConnectionHandler, ConnectionDenied, ConnectionId, DialError, DialFailure,
+        FromSwarm, NetworkBehaviour, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
     },
     Multiaddr, PeerId,
 };
 use lru::LruCache;
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use super::address_policy::AddressPolicy;
+use crate::utils::{poll_ticks, sort_by_dial_preference};
+
+#[derive(Debug, Clone)]
+pub struct AddrCacheConfig {
+    /// Maximum number of cached peers.
+    pub size: NonZeroUsize,
+    /// Addresses not seen for this long are dropped. Configured addresses never expire.
+    pub ttl: Duration,
+    /// Addresses are dropped after this many dial failures in a row.
+    pub max_failures: u32,
+    /// Addresses from identify and the DHT are only cached if the policy allows them.
+    pub policy: AddressPolicy,
+}
+
+/// Where the address was learned from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum AddrSource {
+    /// Boot node or other explicitly configured address
+    Config,
+    /// Listen address reported by the peer itself
+    Identify,
+    /// Address found in the DHT
+    Dht,
+    /// Address we've successfully connected to
+    Observed,
+    /// Address discovered on the local network
+    Mdns,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct AddrInfo {
+    pub source: AddrSource,
+    /// Seconds since UNIX epoch
+    pub last_seen: u64,
+    pub successes: u32,
+    pub failures: u32,
+    /// Failures since the last successful connection
+    pub consecutive_failures: u32,
+}
+
+impl AddrInfo {
+    fn new(source: AddrSource) -> Self {
+        Self {
+            source,
+            last_seen: unix_secs(SystemTime::now()),
+            successes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+        }
+    }
+
+    /// Higher is better. Each successful connection adds a point (up to 10),
+    /// each recent failure takes away two.
+    pub fn score(&self) -> i64 {
+        let source_bonus = match self.source {
+            AddrSource::Config | AddrSource::Observed => 1,
+            AddrSource::Identify | AddrSource::Dht | AddrSource::Mdns => 0,
+        };
+        i64::from(self.successes.min(10)) - 2 * i64::from(self.consecutive_failures) + source_bonus
+    }
+
+    fn is_expired(&self, oldest: u64) -> bool {
+        self.source != AddrSource::Config && self.last_seen < oldest
+    }
+}
 
 pub struct AddressCache {
-    cache: LruCache<PeerId, HashSet<Multiaddr>>,
+    cache: LruCache<PeerId, HashMap<Multiaddr, AddrInfo>>,
+    ttl: Duration,
+    max_failures: u32,
+    policy: AddressPolicy,
+    persistence: Option<Persistence>,
+}
+
+struct Persistence {
+    path: PathBuf,
+    save_interval: Interval,
+}
+
+#[derive(Encode, Decode)]
+struct StoredEntry {
+    peer_id: Vec<u8>,
+    addrs: Vec<(Vec<u8>, AddrInfo)>,
 }
 
 impl AddressCache {
-    pub fn new(size: NonZeroUsize) -> Self {
+    pub fn new(config: AddrCacheConfig) -> Self {
         Self {
-            cache: LruCache::new(size),
+            cache: LruCache::new(config.size),
+            ttl: config.ttl,
+            max_failures: config.max_failures,
+            policy: config.policy,
+            persistence: None,
+        }
+    }
+
+    /// Load the cache from `path`, skipping expired addresses.
+    /// The cache is saved back every `save_interval` and when dropped.
+    pub fn persistent(
+        config: AddrCacheConfig,
+        path: impl Into<PathBuf>,
+        save_interval: Duration,
+    ) -> Self {
+        let path = path.into();
+        let mut cache = Self::new(config);
+        match load(&path) {
+            Ok(entries) => {
+                for (peer_id, addrs) in entries {
+                    cache.cache.put(peer_id, addrs);
+                }
+                cache.remove_expired();
+                log::info!(
+                    "Loaded {} cached peers from {}",
+                    cache.cache.len(),
+                    path.display()
+                );
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Couldn't load address cache from {}: {e}", path.display()),
+        }
+        let mut save_interval = interval(save_interval);
+        save_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        cache.persistence = Some(Persistence {
+            path,
+            save_interval,
+        });
+        cache
+    }
+
+    pub fn put(
+        &mut self,
+        peer_id: PeerId,
+        addrs: impl IntoIterator<Item = Multiaddr>,
+        source: AddrSource,
+    ) {
+        let entry = self.cache.get_or_insert_mut(peer_id, Default::default);
+        let now = unix_secs(SystemTime::now());
+        for addr in addrs {
+            // Configured, discovered on the local network or connected to, so known to work
+            let trusted = !matches!(source, AddrSource::Identify | AddrSource::Dht);
+            if !trusted && !self.policy.allows(&addr) {
+                log::trace!("Not caching address {addr} of {peer_id}, denied by policy");
+                continue;
+            }
+            let info = entry
+                .entry(without_p2p(addr))
+                .or_insert_with(|| AddrInfo::new(source));
+            info.last_seen = now;
+            // Keep the most trustworthy source
+            if source == AddrSource::Config || info.source != AddrSource::Config {
+                info.source = source;
+            }
         }
     }
 
-    pub fn put(&mut self, peer_id: PeerId, addrs: impl IntoIterator<Item = Multiaddr>) {
+    /// Drop the address unless it has since been learned from a more trustworthy source.
+    pub fn remove(&mut self, peer_id: PeerId, addr: &Multiaddr, source: AddrSource) {
+        let Some(addrs) = self.cache.peek_mut(&peer_id) else {
+            return;
+        };
+        let addr = without_p2p(addr.clone());
+        if addrs.get(&addr).is_some_and(|info| info.source == source) {
+            addrs.remove(&addr);
+            if addrs.is_empty() {
+                self.cache.pop(&peer_id);
+            }
+        }
+    }
+
+    /// All cached peers with their addresses, most recently used first.
+    pub fn peers(&self) -> impl Iterator<Item = (PeerId, Vec<Multiaddr>)> + '_ {
         self.cache
-            .get_or_insert_mut(peer_id, Default::default)
-            .extend(addrs)
+            .iter()
+            .map(|(peer_id, addrs)| (*peer_id, addrs.keys().cloned().collect()))
+    }
+
+    pub fn addr_info(&self, peer_id: &PeerId, addr: &Multiaddr) -> Option<&AddrInfo> {
+        self.cache.peek(peer_id)?.get(&without_p2p(addr.clone()))
     }
+
+    fn on_dial_success(&mut self, peer_id: PeerId, addr: &Multiaddr) {
+        self.put(peer_id, iter::once(addr.clone()), AddrSource::Observed);
+        if let Some(info) = self
+            .cache
+            .get_mut(&peer_id)
+            .and_then(|addrs| addrs.get_mut(&without_p2p(addr.clone())))
+        {
+            info.successes = info.successes.saturating_add(1);
+            info.consecutive_failures = 0;
+        }
+    }
+
+    fn on_dial_failure(&mut self, peer_id: PeerId, addr: &Multiaddr) {
+        let Some(addrs) = self.cache.peek_mut(&peer_id) else {
+            return;
+        };
+        let addr = without_p2p(addr.clone());
+        let Some(info) = addrs.get_mut(&addr) else {
+            return;
+        };
+        info.failures = info.failures.saturating_add(1);
+        info.consecutive_failures += 1;
+        if info.source != AddrSource::Config && info.consecutive_failures >= self.max_failures {
+            log::debug!("Dropping address {addr} of {peer_id} after repeated dial failures");
+            addrs.remove(&addr);
+            if addrs.is_empty() {
+                self.cache.pop(&peer_id);
+            }
+        }
+    }
+
+    fn remove_expired(&mut self) {
+        let oldest = unix_secs(oldest_valid(self.ttl));
+        let mut empty = Vec::new();
+        for (peer_id, addrs) in self.cache.iter_mut() {
+            addrs.retain(|_, info| !info.is_expired(oldest));
+            if addrs.is_empty() {
+                empty.push(*peer_id);
+            }
+        }
+        for peer_id in empty {
+            self.cache.pop(&peer_id);
+        }
+    }
+
+    fn save(&mut self) {
+        let Some(path) = self.persistence.as_ref().map(|p| p.path.clone()) else {
+            return;
+        };
+        self.remove_expired();
+        // Stored from least to most recently used, so that loading restores the order
+        let entries: Vec<_> = self
+            .cache
+            .iter()
+            .rev()
+            .map(|(peer_id, addrs)| StoredEntry {
+                peer_id: peer_id.to_bytes(),
+                addrs: addrs
+                    .iter()
+                    .map(|(addr, info)| (addr.to_vec(), info.clone()))
+                    .collect(),
+            })
+            .collect();
+        let tmp_path = path.with_extension("tmp");
+        let result =
+            fs::write(&tmp_path, entries.encode()).and_then(|_| fs::rename(&tmp_path, &path));
+        match result {
+            Ok(()) => log::debug!("Saved {} cached peers", entries.len()),
+            Err(e) => log::warn!("Couldn't save address cache to {}: {e}", path.display()),
+        }
+    }
+}
+
+impl Drop for AddressCache {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+fn load(path: &Path) -> io::Result<Vec<(PeerId, HashMap<Multiaddr, AddrInfo>)>> {
+    let bytes = fs::read(path)?;
+    let stored = Vec::<StoredEntry>::decode(&mut &bytes[..])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let entries = stored
+        .into_iter()
+        .filter_map(|e| {
+            let peer_id = PeerId::from_bytes(&e.peer_id).ok()?;
+            let addrs = e
+                .addrs
+                .into_iter()
+                .filter_map(|(addr, info)| Some((Multiaddr::try_from(addr).ok()?, info)))
+                .collect();
+            Some((peer_id, addrs))
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// Entries last seen before this time are expired. A TTL reaching before the epoch keeps all.
+fn oldest_valid(ttl: Duration) -> SystemTime {
+    SystemTime::now().checked_sub(ttl).unwrap_or(UNIX_EPOCH)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Addresses are cached without the peer ID suffix.
+fn without_p2p(mut addr: Multiaddr) -> Multiaddr {
+    if let Some(Protocol::P2p(_)) = addr.iter().last() {
+        addr.pop();
+    }
+    addr
 }
 
 impl NetworkBehaviour for AddressCache {
@@ -57,12 +343,24 @@ impl NetworkBehaviour for AddressCache {
         let Some(peer_id) = maybe_peer else {
             return Ok(Vec::new());
         };
-        let addrs = self
-            .cache
-            .get(&peer_id)
-            .map(|a| a.iter().cloned().collect())
-            .unwrap_or_default();
-        Ok(addrs)
+        let oldest = unix_secs(oldest_valid(self.ttl));
+        let Some(cached) = self.cache.get(&peer_id) else {
+            return Ok(Vec::new());
+        };
+        let mut addrs: Vec<_> = cached
+            .iter()
+            .filter(|(_, info)| !info.is_expired(oldest))
+            .map(|(addr, info)| (addr.clone(), info.score()))
+            .collect();
+        // Best score first, ties broken by transport preference
+        addrs.sort_by_key(|(_, score)| -score);
+        let mut sorted: Vec<Multiaddr> = Vec::with_capacity(addrs.len());
+        for group in addrs.chunk_by(|(_, a), (_, b)| a == b) {
+            let mut group: Vec<_> = group.iter().map(|(addr, _)| addr.clone()).collect();
+            sort_by_dial_preference(&mut group);
+            sorted.extend(group);
+        }
+        Ok(sorted)
     }
 
     fn handle_established_outbound_connection(
@@ -73,13 +371,25 @@ impl NetworkBehaviour for AddressCache {
         _role_override: Endpoint,
         _port_use: PortUse,
     ) -> Result<THandler<Self>, ConnectionDenied> {
-        self.put(peer, iter::once(addr.clone()));
+        self.on_dial_success(peer, addr);
         Ok(ConnectionHandler)
     }
 
     fn on_swarm_event(&mut self, event: FromSwarm) {
-        if let FromSwarm::NewExternalAddrOfPeer(e) = event {
-            self.put(e.peer_id, iter::once(e.addr.clone()))
+        match event {
+            FromSwarm::NewExternalAddrOfPeer(e) => {
+                self.put(e.peer_id, iter::once(e.addr.clone()), AddrSource::Identify)
+            }
+            FromSwarm::DialFailure(DialFailure {
+                peer_id: Some(peer_id),
+                error: DialError::Transport(errors),
+                ..
+            }) => {
+                for (addr, _) in errors {
+                    self.on_dial_failure(peer_id, addr);
+                }
+            }
+            _ => {}
         }
     }
 
@@ -93,8 +403,136 @@ impl NetworkBehaviour for AddressCache {
 
     fn poll(
         &mut self,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        let save = self
+            .persistence
+            .as_mut()
+            .is_some_and(|p| poll_ticks(&mut p.save_interval, cx));
+        if save {
+            self.save();
+        }
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AddrCacheConfig {
+        AddrCacheConfig {
+            size: NonZeroUsize::new(10).unwrap(),
+            ttl: Duration::from_secs(3600),
+            max_failures: 2,
+            policy: Default::default(),
+        }
+    }
+
+    fn dial_addrs(cache: &mut AddressCache, peer_id: PeerId) -> Vec<Multiaddr> {
+        cache
+            .handle_pending_outbound_connection(
+                ConnectionId::new_unchecked(0),
+                Some(peer_id),
+                &[],
+                Endpoint::Dialer,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_scoring() {
+        let mut cache = AddressCache::new(config());
+        let peer_id = PeerId::random();
+        let (good, bad, tcp): (Multiaddr, Multiaddr, Multiaddr) = (
+            "/ip4/1.2.3.4/udp/1/quic-v1".parse().unwrap(),
+            "/ip4/1.2.3.5/udp/1/quic-v1".parse().unwrap(),
+            "/ip4/1.2.3.4/tcp/1".parse().unwrap(),
+        );
+        cache.put(
+            peer_id,
+            [tcp.clone(), bad.clone(), good.clone()],
+            AddrSource::Dht,
+        );
+        assert_eq!(dial_addrs(&mut cache, peer_id)[2], tcp);
+
+        cache.on_dial_success(peer_id, &good.clone().with(Protocol::P2p(peer_id)));
+        cache.on_dial_failure(peer_id, &bad);
+        assert_eq!(
+            dial_addrs(&mut cache, peer_id),
+            [good.clone(), tcp.clone(), bad.clone()]
+        );
+        let info = cache.addr_info(&peer_id, &good).unwrap();
+        assert_eq!((info.successes, info.source), (1, AddrSource::Observed));
+
+        // Dropped after `max_failures` failures in a row
+        cache.on_dial_failure(peer_id, &bad);
+        assert_eq!(dial_addrs(&mut cache, peer_id), [good, tcp]);
+    }
+
+    #[test]
+    fn test_policy() {
+        let mut cache = AddressCache::new(config());
+        let peer_id = PeerId::random();
+        let (public, private): (Multiaddr, Multiaddr) = (
+            "/ip4/1.2.3.4/udp/1/quic-v1".parse().unwrap(),
+            "/ip4/192.168.0.1/udp/1/quic-v1".parse().unwrap(),
+        );
+        cache.put(peer_id, [public.clone(), private.clone()], AddrSource::Dht);
+        assert_eq!(
+            dial_addrs(&mut cache, peer_id),
+            std::slice::from_ref(&public)
+        );
+
+        // Configured addresses are kept regardless
+        cache.put(peer_id, [private.clone()], AddrSource::Config);
+        assert_eq!(dial_addrs(&mut cache, peer_id), [private, public]);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut cache = AddressCache::new(config());
+        let peer_id = PeerId::random();
+        let (boot, dht): (Multiaddr, Multiaddr) = (
+            "/ip4/1.2.3.4/udp/1/quic-v1".parse().unwrap(),
+            "/ip4/1.2.3.5/udp/1/quic-v1".parse().unwrap(),
+        );
+        cache.put(peer_id, [boot.clone()], AddrSource::Config);
+        cache.put(peer_id, [dht.clone()], AddrSource::Dht);
+        for info in cache.cache.get_mut(&peer_id).unwrap().values_mut() {
+            info.last_seen -= 7200;
+        }
+        assert_eq!(dial_addrs(&mut cache, peer_id), std::slice::from_ref(&boot));
+
+        // A TTL reaching before the epoch keeps everything instead of overflowing
+        cache.ttl = Duration::from_secs(u64::MAX);
+        assert_eq!(dial_addrs(&mut cache, peer_id), [boot, dht]);
+    }
+
+    #[tokio::test]
+    async fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("addr-cache-{}", rand::random::<u64>()));
+        let interval = Duration::from_secs(3600);
+        let (fresh, stale) = (PeerId::random(), PeerId::random());
+        let addr: Multiaddr = "/memory/1".parse().unwrap();
+
+        let mut cache = AddressCache::persistent(config(), &path, interval);
+        cache.put(stale, [addr.clone()], AddrSource::Dht);
+        cache.put(fresh, [addr.clone()], AddrSource::Dht);
+        cache
+            .cache
+            .get_mut(&stale)
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .last_seen -= 7200;
+        drop(cache);
+
+        let cache = AddressCache::persistent(config(), &path, interval);
+        let peers: Vec<_> = cache.peers().collect();
+        assert_eq!(peers, [(fresh, vec![addr])]);
+
+        drop(cache);
+        fs::remove_file(path).unwrap();
+    }
+}
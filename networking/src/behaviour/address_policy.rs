@@ -0,0 +1,218 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use clap::ValueEnum;
+use ipnet::IpNet;
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::EnvReader, protocol::TransportKind};
+
+/// Where an IP address can be reached from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IpScope {
+    /// Loopback, link-local, unspecified, multicast and documentation addresses
+    Local,
+    /// Private networks (RFC 1918), shared address space (RFC 6598) and IPv6 unique local addresses
+    Private,
+    Global,
+}
+
+impl From<Ipv4Addr> for IpScope {
+    fn from(ip: Ipv4Addr) -> Self {
+        let [a, b, ..] = ip.octets();
+        if ip.is_loopback()
+            || ip.is_link_local()
+            || ip.is_unspecified()
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || ip.is_documentation()
+        {
+            Self::Local
+        } else if ip.is_private() || (a == 100 && b & 0xc0 == 64) {
+            Self::Private
+        } else {
+            Self::Global
+        }
+    }
+}
+
+impl From<Ipv6Addr> for IpScope {
+    fn from(ip: Ipv6Addr) -> Self {
+        if let Some(ip) = ip.to_ipv4_mapped() {
+            return ip.into();
+        }
+        let [a, b, ..] = ip.segments();
+        if ip.is_loopback()
+            || ip.is_unspecified()
+            || ip.is_multicast()
+            || ip.is_unicast_link_local()
+            || (a == 0x2001 && b == 0x0db8)
+        {
+            Self::Local
+        } else if ip.is_unique_local() {
+            Self::Private
+        } else {
+            Self::Global
+        }
+    }
+}
+
+/// Decides which addresses of other peers are worth keeping and passing on.
+/// Applied to addresses from identify and the DHT.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AddressPolicy {
+    /// Accept addresses in private networks, e.g. for testing in a local environment (default: false).
+    pub allow_private: bool,
+    /// Networks accepted even if they're private or local.
+    pub allow: Vec<IpNet>,
+    /// Networks never accepted. Takes precedence over `allow`.
+    pub deny: Vec<IpNet>,
+    /// Only accept addresses using these transports. Empty accepts all (default).
+    pub transports: Vec<TransportKind>,
+    /// Accept `/dns`, `/dns4`, `/dns6` and `/dnsaddr` addresses (default: true).
+    pub allow_dns: bool,
+}
+
+impl Default for AddressPolicy {
+    fn default() -> Self {
+        Self {
+            allow_private: false,
+            allow: vec![],
+            deny: vec![],
+            transports: vec![],
+            allow_dns: true,
+        }
+    }
+}
+
+impl AddressPolicy {
+    /// Override the settings given in the environment.
+    pub fn apply_env(&mut self, env: &mut EnvReader) {
+        // `PRIVATE_NETWORK` is the name used before the policy was configurable
+        self.allow_private |= std::env::var("PRIVATE_NETWORK").is_ok();
+        env.var("ADDR_ALLOW_PRIVATE", &mut self.allow_private);
+        env.list("ADDR_ALLOW", &mut self.allow, str::parse);
+        env.list("ADDR_DENY", &mut self.deny, str::parse);
+        env.list("ADDR_TRANSPORTS", &mut self.transports, |s| {
+            <TransportKind as ValueEnum>::from_str(s, true)
+        });
+        env.var("ADDR_ALLOW_DNS", &mut self.allow_dns);
+    }
+
+    pub fn allows(&self, addr: &Multiaddr) -> bool {
+        if !self.transports.is_empty()
+            && !TransportKind::of(addr).is_some_and(|kind| self.transports.contains(&kind))
+        {
+            return false;
+        }
+        match addr.iter().next() {
+            Some(Protocol::Ip4(ip)) => self.allows_ip(ip.into(), ip.into()),
+            Some(Protocol::Ip6(ip)) => self.allows_ip(ip.into(), ip.into()),
+            Some(
+                Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) | Protocol::Dnsaddr(_),
+            ) => self.allow_dns,
+            // Only used in-process, by nodes running the memory transport
+            Some(Protocol::Memory(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn allows_ip(&self, ip: IpAddr, scope: IpScope) -> bool {
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        if self.allow.iter().any(|net| net.contains(&ip)) {
+            return true;
+        }
+        match scope {
+            IpScope::Local => false,
+            IpScope::Private => self.allow_private,
+            IpScope::Global => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(policy: &AddressPolicy, addrs: &[&str]) -> Vec<bool> {
+        addrs
+            .iter()
+            .map(|addr| policy.allows(&addr.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_ip_scopes() {
+        let local = [
+            "/ip4/127.0.0.1/tcp/1",
+            "/ip4/169.254.0.1/tcp/1",
+            "/ip4/0.0.0.0/tcp/1",
+            "/ip6/::1/tcp/1",
+            "/ip6/fe80::1/tcp/1",
+            "/ip6/ff02::1/tcp/1",
+            "/ip6/2001:db8::1/tcp/1",
+            "/ip6/::ffff:127.0.0.1/tcp/1",
+        ];
+        let private = [
+            "/ip4/10.0.0.1/tcp/1",
+            "/ip4/172.16.0.1/tcp/1",
+            "/ip4/192.168.0.1/tcp/1",
+            "/ip4/100.64.0.1/tcp/1",
+            "/ip6/fd00::1/tcp/1",
+            "/ip6/fc00::1/tcp/1",
+        ];
+        let global = [
+            "/ip4/1.2.3.4/tcp/1",
+            "/ip6/2a01:4f8::1/udp/1/quic-v1",
+            "/dns4/example.org/tcp/443/wss",
+            "/memory/1234",
+        ];
+
+        let policy = AddressPolicy::default();
+        assert!(!allowed(&policy, &local).contains(&true));
+        assert!(!allowed(&policy, &private).contains(&true));
+        assert!(!allowed(&policy, &global).contains(&false));
+
+        let policy = AddressPolicy {
+            allow_private: true,
+            ..Default::default()
+        };
+        assert!(!allowed(&policy, &local).contains(&true));
+        assert!(!allowed(&policy, &private).contains(&false));
+        assert!(!allowed(&policy, &["/p2p-circuit", "/unix/tmp%2Fsock"]).contains(&true));
+    }
+
+    #[test]
+    fn test_allow_and_deny() {
+        let policy = AddressPolicy {
+            allow: vec!["10.1.0.0/16".parse().unwrap(), "::1/128".parse().unwrap()],
+            deny: vec![
+                "10.1.2.0/24".parse().unwrap(),
+                "1.2.3.0/24".parse().unwrap(),
+            ],
+            transports: vec![TransportKind::Quic, TransportKind::Ws],
+            allow_dns: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            allowed(
+                &policy,
+                &[
+                    "/ip4/10.1.1.1/udp/1/quic-v1",
+                    "/ip6/::1/udp/1/quic-v1",
+                    "/ip4/10.1.2.1/udp/1/quic-v1",
+                    "/ip4/10.2.0.1/udp/1/quic-v1",
+                    "/ip4/1.2.3.4/udp/1/quic-v1",
+                    "/ip4/5.6.7.8/udp/1/quic-v1",
+                    "/ip4/5.6.7.8/tcp/1",
+                    "/ip4/5.6.7.8/tcp/443/wss",
+                    "/dns4/example.org/tcp/443/wss",
+                ]
+            ),
+            [true, true, false, false, false, true, false, true, false]
+        );
+    }
+}
@@ -1,112 +1,289 @@
 use std::{
     collections::{HashMap, HashSet, VecDeque},
     num::NonZeroUsize,
+    path::PathBuf,
     sync::Arc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
     vec,
 };
 
 use bimap::BiHashMap;
 use codec::Encode;
-use futures_bounded::FuturesMap;
+use futures::{future::BoxFuture, FutureExt};
+use futures_bounded::{FuturesMap, PushError};
 use libp2p::{
     autonat::{self, NatStatus},
     core::ConnectedPoint,
     dcutr, identify,
     identity::Keypair,
     kad::{
-        self, store::MemoryStore, GetClosestPeersError, GetClosestPeersOk, ProgressStep, QueryId,
-        QueryResult,
+        self,
+        store::{MemoryStoreConfig, RecordStore},
+        AddProviderError, GetClosestPeersError, GetClosestPeersOk, GetProvidersError,
+        GetProvidersOk, GetRecordOk, InboundRequest, ProgressStep, PutRecordError, QueryId,
+        QueryResult, Quorum, RecordKey, StoreInserts,
     },
-    ping, relay,
+    mdns, ping, relay,
     swarm::{
-        behaviour::ConnectionEstablished,
+        behaviour::{toggle::Toggle, ConnectionEstablished},
         dial_opts::{DialOpts, PeerCondition},
-        ConnectionClosed, ConnectionId, DialFailure, FromSwarm, NetworkBehaviour, ToSwarm,
+        CloseConnection, ConnectionClosed, ConnectionId, DialError, DialFailure, FromSwarm,
+        NetworkBehaviour, ToSwarm,
     },
     Multiaddr, PeerId, StreamProtocol,
 };
 use libp2p_swarm_derive::NetworkBehaviour;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds, DurationSeconds};
+use tokio::time::{interval, interval_at, Instant, Interval, MissedTickBehavior};
 
 use super::{
-    addr_cache::AddressCache,
+    addr_cache::{AddrCacheConfig, AddrSource, AddressCache},
+    address_policy::AddressPolicy,
+    conn_manager::{ConnManagerConfig, ConnManagerEvent, ConnectionManager},
+    external_addrs::{AddrCandidate, ExternalAddrManager},
+    latency::{LatencyTracker, PeerLatency},
+    probe_queue::{ProbeHandle, ProbePriority, ProbeQueue, ProbeTarget, QueuedProbe},
     pubsub::{MsgValidationConfig, PubsubBehaviour, PubsubMsg, ValidationError},
+    reachability::{ReachabilityMatrix, ReachabilityMonitor},
+    record::{check_newer, check_replay, sign_record, verify_record, DhtRecord, InvalidRecord},
+    record_store::PersistentStore,
+    relay_manager::{RelayManager, RelayManagerConfig, RelayReservation},
+    relay_server::{relay_server, RelayServerConfig},
     whitelist::{WhitelistBehavior, WhitelistConfig},
     wrapped::{BehaviourWrapper, TToSwarm, Wrapped},
 };
 
-use crate::utils::addr_is_reachable;
-
 use super::super::{
+    boot_nodes::{BootNodeResolver, ResolvedBootNodes},
     chain_client::{AuthorityPeers, ContractClient},
     cli::BootNode,
+    config::EnvReader,
     protocol::{ID_PROTOCOL, KNOWN_TOPICS, MAX_PUBSUB_MSG_SIZE},
-    utils::parse_env_var,
-    AgentInfo,
+    utils::poll_ticks,
+    AgentInfo, Error,
 };
 
 #[derive(NetworkBehaviour)]
 pub struct InnerBehaviour {
     identify: identify::Behaviour,
-    kademlia: kad::Behaviour<MemoryStore>,
+    kademlia: kad::Behaviour<PersistentStore>,
     relay: relay::client::Behaviour,
+    relay_server: Toggle<relay::Behaviour>,
     dcutr: dcutr::Behaviour,
     ping: ping::Behaviour,
     autonat: autonat::Behaviour,
     whitelist: Wrapped<WhitelistBehavior>,
     pubsub: Wrapped<PubsubBehaviour>,
     address_cache: AddressCache,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    conn_manager: Toggle<ConnectionManager>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct BaseConfig {
-    /// How often to check for on-chain updates
+    /// How often to check for on-chain updates (default: 60 sec).
+    #[serde_as(as = "DurationSeconds<u64>")]
     pub onchain_update_interval: Duration,
     /// Timeout for autoNAT probes (default: 60 sec).
+    #[serde_as(as = "DurationSeconds<u64>")]
     pub autonat_timeout: Duration,
     /// How often to publish identify info to connected nodes (default: 60 sec).
+    #[serde_as(as = "DurationSeconds<u64>")]
     pub identify_interval: Duration,
     /// Timeout for outgoing reachability probes (default: 20 sec).
+    #[serde_as(as = "DurationSeconds<u64>")]
     pub probe_timeout: Duration,
-    /// Timeout for kademlia DHT queries (default: 10 sec).
+    /// Timeout for kademlia DHT queries (default: 5 sec).
+    #[serde_as(as = "DurationSeconds<u64>")]
     pub kad_query_timeout: Duration,
     /// Maximum number of concurrent outgoing reachability probes (default: 1024)
     pub max_concurrent_probes: usize,
+    /// Maximum number of probes waiting for a free slot (default: 4096)
+    pub max_queued_probes: usize,
     /// Maximum size of gossipsub messages in bytes (default: `MAX_PUBSUB_MSG_SIZE`)
     pub max_pubsub_msg_size: usize,
     /// Maximum number of peers to keep in the address cache (default: 1024)
     pub addr_cache_size: NonZeroUsize,
-    /// Minimum interval between messages from the same origin
+    /// Minimum interval between messages from the same origin (default: 50 ms).
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
     pub msg_interval: Duration,
+    /// Run a circuit relay v2 server for authorities (default: disabled)
+    pub relay_server: Option<RelayServerConfig>,
+    /// Always act as a Kademlia server, instead of deciding based on
+    /// confirmed external addresses (default: false)
+    pub kad_server_mode: bool,
+    /// How often to bootstrap the DHT and run a random-walk query (default: 5 min).
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub kad_bootstrap_interval: Duration,
+    /// Routing table size at which the DHT counts as bootstrapped (default: 3).
+    pub kad_min_routing_table_size: usize,
+    /// Maximum number of DHT records stored locally (default: 1024).
+    pub kad_max_records: usize,
+    /// Maximum size of a single DHT record value in bytes (default: 65 KiB).
+    pub kad_max_record_bytes: usize,
+    /// Discover peers on the local network with mDNS (default: false, enabled for local network).
+    pub mdns: bool,
+    /// Keep connections to all registered authorities open (default: false).
+    pub maintain_authority_connections: bool,
+    /// Delay before redialing an authority, doubled after every failure (default: 1 sec).
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub reconnect_backoff_min: Duration,
+    /// Maximum delay between attempts to redial an authority (default: 5 min).
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub reconnect_backoff_max: Duration,
+    /// How often to save the address cache to `data_dir` (default: 1 min).
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub addr_cache_save_interval: Duration,
+    /// Cached addresses not seen for this long expire (default: 1 day).
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub addr_cache_ttl: Duration,
+    /// Cached addresses are dropped after this many dial failures in a row (default: 3).
+    pub addr_cache_max_failures: u32,
+    /// Directory in which DHT records and the address cache are persisted.
+    /// If not set, they're only kept in memory.
+    pub data_dir: Option<PathBuf>,
+    /// How often to resolve `/dnsaddr` boot nodes and re-read the boot nodes file (default: 10 min).
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub boot_node_refresh_interval: Duration,
+    /// How often to probe all registered authorities (default: disabled). Zero disables it as well.
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub reachability_interval: Option<Duration>,
+    /// How often to ping connected peers (default: 15 sec).
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub ping_interval: Duration,
+    /// Peers are disconnected after this many failed pings in a row, 0 disables it (default: 3).
+    pub ping_max_failures: u32,
+    /// Listen on relays only while AutoNAT finds the node private, instead of
+    /// always listening on them when relaying is enabled (default: false).
+    pub auto_relay: bool,
+    /// Number of relays to hold reservations on at the same time (default: 2).
+    pub relay_reservations: usize,
+    /// Delay before retrying a relay which failed or refused a reservation (default: 30 sec).
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub relay_retry_interval: Duration,
+    /// Close relayed connections to a peer once a direct connection is established (default: true).
+    pub prefer_direct_connections: bool,
+    /// Which addresses of other peers are cached and added to the DHT.
+    pub address_policy: AddressPolicy,
+    /// Observed external addresses expire unless AutoNAT confirms them again
+    /// within this time (default: 1 hour).
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub external_addr_ttl: Duration,
 }
 
-impl BaseConfig {
-    pub fn from_env() -> Self {
-        let onchain_update_interval =
-            Duration::from_secs(parse_env_var("ONCHAIN_UPDATE_INTERVAL_SEC", 60));
-        let autonat_timeout = Duration::from_secs(parse_env_var("AUTONAT_TIMEOUT_SEC", 60));
-        let identify_interval = Duration::from_secs(parse_env_var("IDENTIFY_INTERVAL_SEC", 60));
-        let probe_timeout = Duration::from_secs(parse_env_var("PROBE_TIMEOUT_SEC", 20));
-        let kad_query_timeout = Duration::from_secs(parse_env_var("KAD_QUERY_TIMEOUT_SEC", 5));
-        let max_concurrent_probes = parse_env_var("MAX_CONCURRENT_PROBES", 1024);
-        let max_pubsub_msg_size = parse_env_var("MAX_PUBSUB_MSG_SIZE", MAX_PUBSUB_MSG_SIZE);
-        let addr_cache_size = NonZeroUsize::new(parse_env_var("ADDR_CACHE_SIZE", 1024))
-            .expect("addr_cache_size should be > 0");
-        let msg_interval = Duration::from_millis(parse_env_var("MSG_INTERVAL_MILLI", 50));
+impl Default for BaseConfig {
+    fn default() -> Self {
         Self {
-            onchain_update_interval,
-            autonat_timeout,
-            identify_interval,
-            probe_timeout,
-            kad_query_timeout,
-            max_concurrent_probes,
-            max_pubsub_msg_size,
-            addr_cache_size,
-            msg_interval,
+            onchain_update_interval: Duration::from_secs(60),
+            autonat_timeout: Duration::from_secs(60),
+            identify_interval: Duration::from_secs(60),
+            probe_timeout: Duration::from_secs(20),
+            kad_query_timeout: Duration::from_secs(5),
+            max_concurrent_probes: 1024,
+            max_queued_probes: 4096,
+            max_pubsub_msg_size: MAX_PUBSUB_MSG_SIZE,
+            addr_cache_size: NonZeroUsize::new(1024).unwrap(),
+            msg_interval: Duration::from_millis(50),
+            relay_server: None,
+            kad_server_mode: false,
+            kad_bootstrap_interval: Duration::from_secs(300),
+            kad_min_routing_table_size: 3,
+            kad_max_records: 1024,
+            kad_max_record_bytes: 65 * 1024,
+            mdns: false,
+            maintain_authority_connections: false,
+            reconnect_backoff_min: Duration::from_secs(1),
+            reconnect_backoff_max: Duration::from_secs(300),
+            addr_cache_save_interval: Duration::from_secs(60),
+            addr_cache_ttl: Duration::from_secs(24 * 3600),
+            addr_cache_max_failures: 3,
+            data_dir: None,
+            boot_node_refresh_interval: Duration::from_secs(600),
+            reachability_interval: None,
+            ping_interval: Duration::from_secs(15),
+            ping_max_failures: 3,
+            auto_relay: false,
+            relay_reservations: 2,
+            relay_retry_interval: Duration::from_secs(30),
+            prefer_direct_connections: true,
+            address_policy: Default::default(),
+            external_addr_ttl: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl BaseConfig {
+    /// Override the settings given in the environment.
+    pub fn apply_env(&mut self, env: &mut EnvReader) {
+        env.secs(
+            "ONCHAIN_UPDATE_INTERVAL_SEC",
+            &mut self.onchain_update_interval,
+        );
+        env.secs("AUTONAT_TIMEOUT_SEC", &mut self.autonat_timeout);
+        env.secs("IDENTIFY_INTERVAL_SEC", &mut self.identify_interval);
+        env.secs("PROBE_TIMEOUT_SEC", &mut self.probe_timeout);
+        env.secs("KAD_QUERY_TIMEOUT_SEC", &mut self.kad_query_timeout);
+        env.var("MAX_CONCURRENT_PROBES", &mut self.max_concurrent_probes);
+        env.var("MAX_QUEUED_PROBES", &mut self.max_queued_probes);
+        env.var("MAX_PUBSUB_MSG_SIZE", &mut self.max_pubsub_msg_size);
+        env.var("ADDR_CACHE_SIZE", &mut self.addr_cache_size);
+        env.millis("MSG_INTERVAL_MILLI", &mut self.msg_interval);
+        // Read even if disabled, so that invalid values are reported
+        let mut relay_server = self.relay_server.unwrap_or_default();
+        relay_server.apply_env(env);
+        let mut enabled = self.relay_server.is_some();
+        env.var("RELAY_SERVER", &mut enabled);
+        self.relay_server = enabled.then_some(relay_server);
+        env.var("KAD_SERVER_MODE", &mut self.kad_server_mode);
+        env.secs(
+            "KAD_BOOTSTRAP_INTERVAL_SEC",
+            &mut self.kad_bootstrap_interval,
+        );
+        env.var(
+            "KAD_MIN_ROUTING_TABLE_SIZE",
+            &mut self.kad_min_routing_table_size,
+        );
+        env.var("KAD_MAX_RECORDS", &mut self.kad_max_records);
+        env.var("KAD_MAX_RECORD_BYTES", &mut self.kad_max_record_bytes);
+        env.secs(
+            "ADDR_CACHE_SAVE_INTERVAL_SEC",
+            &mut self.addr_cache_save_interval,
+        );
+        env.secs("ADDR_CACHE_TTL_SEC", &mut self.addr_cache_ttl);
+        env.var("ADDR_CACHE_MAX_FAILURES", &mut self.addr_cache_max_failures);
+        if let Some(data_dir) = std::env::var_os("DATA_DIR") {
+            self.data_dir = Some(data_dir.into());
         }
+        env.secs(
+            "BOOT_NODE_REFRESH_INTERVAL_SEC",
+            &mut self.boot_node_refresh_interval,
+        );
+        env.secs("PING_INTERVAL_SEC", &mut self.ping_interval);
+        env.var("PING_MAX_FAILURES", &mut self.ping_max_failures);
+        env.var("AUTO_RELAY", &mut self.auto_relay);
+        env.var("RELAY_RESERVATIONS", &mut self.relay_reservations);
+        env.secs("RELAY_RETRY_INTERVAL_SEC", &mut self.relay_retry_interval);
+        env.var(
+            "PREFER_DIRECT_CONNECTIONS",
+            &mut self.prefer_direct_connections,
+        );
+        self.address_policy.apply_env(env);
+        env.secs("EXTERNAL_ADDR_TTL_SEC", &mut self.external_addr_ttl);
+        if let Some(secs) = env.parse("REACHABILITY_INTERVAL_SEC") {
+            self.reachability_interval = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        env.var("MDNS", &mut self.mdns);
+        env.var(
+            "MAINTAIN_AUTHORITY_CONNECTIONS",
+            &mut self.maintain_authority_connections,
+        );
+        env.millis("RECONNECT_BACKOFF_MIN_MS", &mut self.reconnect_backoff_min);
+        env.secs("RECONNECT_BACKOFF_MAX_SEC", &mut self.reconnect_backoff_max);
     }
 }
 
@@ -116,28 +293,97 @@ pub struct BaseBehaviour {
     msg_interval: Duration,
     pending_events: VecDeque<TToSwarm<Self>>,
     pending_outbound_conns: BiHashMap<PeerId, ConnectionId>,
+    /// Dials of cancelled probes, closed as soon as they connect
+    cancelled_dials: HashSet<ConnectionId>,
+    /// Dials of found providers, whose addresses are cached when dialing
+    provider_dials: HashSet<ConnectionId>,
     ongoing_queries: BiHashMap<PeerId, QueryId>,
     outbound_conns: HashMap<PeerId, u32>,
     probe_timeouts: FuturesMap<PeerId, ()>,
+    probe_start_times: HashMap<PeerId, Instant>,
+    probe_queue: ProbeQueue,
+    max_concurrent_probes: usize,
     registered_nodes: Arc<RwLock<HashSet<PeerId>>>,
+    bootstrap_interval: Interval,
+    min_routing_table_size: usize,
+    dht_bootstrapped: bool,
+    record_queries: HashMap<QueryId, RecordQuery>,
+    /// Sequence number of the latest record published by this node
+    record_seq: u64,
+    provider_queries: HashMap<QueryId, (RecordKey, HashSet<PeerId>)>,
+    /// Live mDNS addresses of local peers. The cache isn't enough to tell, because the
+    /// source of an address changes once it has been dialed.
+    mdns_peers: HashMap<PeerId, HashSet<Multiaddr>>,
+    boot_nodes: HashSet<(PeerId, Multiaddr)>,
+    /// Boot nodes given by the resolver, dropped once it stops listing them
+    resolved_boot_nodes: HashSet<(PeerId, Multiaddr)>,
+    boot_node_resolver: Option<BootNodeResolver>,
+    boot_node_refresh: Interval,
+    boot_node_lookup: Option<BoxFuture<'static, ResolvedBootNodes>>,
+    reachability: Option<ReachabilityMonitor>,
+    latencies: LatencyTracker,
+    ping_max_failures: u32,
+    relay_manager: Option<RelayManager>,
+    relay_manager_config: RelayManagerConfig,
+    /// Type and remote address of all connections to each peer
+    peer_connections: HashMap<PeerId, HashMap<ConnectionId, (ConnectionType, Multiaddr)>>,
+    prefer_direct_connections: bool,
+    hole_punch_stats: HolePunchStats,
+    external_addrs: ExternalAddrManager,
+    address_policy: AddressPolicy,
 }
 
 #[allow(dead_code)]
 impl BaseBehaviour {
     pub fn new(
         keypair: &Keypair,
-        // Evalutate if you need it after for eth contracts
-        // contract_client: Box<dyn ContractClient>,
+        contract_client: ContractClient,
         config: BaseConfig,
         boot_nodes: Vec<BootNode>,
         relay: relay::client::Behaviour,
         dht_protocol: StreamProtocol,
         agent_info: AgentInfo,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         let local_peer_id = keypair.public().to_peer_id();
         log::info!("Local peer id: {local_peer_id}");
         let mut kad_config = kad::Config::new(dht_protocol);
         kad_config.set_query_timeout(config.kad_query_timeout);
+        // Bootstrapping is driven by `bootstrap_interval`
+        kad_config.set_periodic_bootstrap_interval(None);
+        // Only records signed by registered authorities are stored
+        kad_config.set_record_filtering(StoreInserts::FilterBoth);
+        let store_config = MemoryStoreConfig {
+            max_records: config.kad_max_records,
+            max_value_bytes: config.kad_max_record_bytes,
+            ..Default::default()
+        };
+        let store = match &config.data_dir {
+            Some(dir) => PersistentStore::open(local_peer_id, store_config, dir)
+                .map_err(|e| Error::Store(format!("{}: {e}", dir.display())))?,
+            None => PersistentStore::in_memory(local_peer_id, store_config),
+        };
+        let addr_cache_config = AddrCacheConfig {
+            size: config.addr_cache_size,
+            ttl: config.addr_cache_ttl,
+            max_failures: config.addr_cache_max_failures,
+            policy: config.address_policy.clone(),
+        };
+        let address_cache = match &config.data_dir {
+            Some(dir) => AddressCache::persistent(
+                addr_cache_config,
+                dir.join("address_cache"),
+                config.addr_cache_save_interval,
+            ),
+            None => AddressCache::new(addr_cache_config),
+        };
+        let mdns = config
+            .mdns
+            .then(|| mdns::tokio::Behaviour::new(Default::default(), local_peer_id))
+            .and_then(|res| {
+                res.inspect_err(|e| log::error!("Cannot start mDNS discovery: {e}"))
+                    .ok()
+            });
+        let registered_nodes = Arc::new(RwLock::new(HashSet::new()));
         let mut inner = InnerBehaviour {
             identify: identify::Behaviour::new(
                 identify::Config::new(ID_PROTOCOL.to_string(), keypair.public())
@@ -145,14 +391,16 @@ impl BaseBehaviour {
                     .with_push_listen_addr_updates(true)
                     .with_agent_version(agent_info.to_string()),
             ),
-            kademlia: kad::Behaviour::with_config(
-                local_peer_id,
-                MemoryStore::new(local_peer_id),
-                kad_config,
-            ),
+            kademlia: kad::Behaviour::with_config(local_peer_id, store, kad_config),
             relay,
+            relay_server: config
+                .relay_server
+                .map(|relay_config| {
+                    relay_server(local_peer_id, relay_config, registered_nodes.clone())
+                })
+                .into(),
             dcutr: dcutr::Behaviour::new(local_peer_id),
-            ping: ping::Behaviour::new(ping::Config::default()),
+            ping: ping::Behaviour::new(ping::Config::new().with_interval(config.ping_interval)),
             autonat: autonat::Behaviour::new(
                 local_peer_id,
                 autonat::Config {
@@ -161,33 +409,123 @@ impl BaseBehaviour {
                 },
             ),
             whitelist: WhitelistBehavior::new(
-                // change this accordingly when in production
-                ContractClient::default(),
+                contract_client,
                 WhitelistConfig::new(config.onchain_update_interval),
             )
             .into(),
             pubsub: PubsubBehaviour::new(keypair.clone(), config.max_pubsub_msg_size).into(),
-            address_cache: AddressCache::new(config.addr_cache_size),
+            address_cache,
+            mdns: mdns.into(),
+            conn_manager: config
+                .maintain_authority_connections
+                .then(|| {
+                    let config = ConnManagerConfig {
+                        backoff_min: config.reconnect_backoff_min,
+                        backoff_max: config.reconnect_backoff_max,
+                        check_interval: Duration::from_secs(1),
+                    };
+                    ConnectionManager::new(local_peer_id, config, registered_nodes.clone())
+                })
+                .into(),
         };
 
-        for boot_node in boot_nodes {
-            inner.whitelist.allow_peer(boot_node.peer_id);
-            inner
-                .autonat
-                .add_server(boot_node.peer_id, Some(boot_node.address));
+        if config.kad_server_mode {
+            inner.kademlia.set_mode(Some(kad::Mode::Server));
         }
 
-        Self {
+        // Peers known from before a restart let the DHT bootstrap even if boot nodes are down
+        let cached_peers: Vec<_> = inner
+            .address_cache
+            .peers()
+            .flat_map(|(peer_id, addrs)| addrs.into_iter().map(move |addr| (peer_id, addr)))
+            .collect();
+        for (peer_id, addr) in cached_peers {
+            inner.kademlia.add_address(&peer_id, addr);
+        }
+
+        for boot_node in &boot_nodes {
+            inner.add_boot_node(boot_node);
+        }
+        let mut boot_node_refresh = interval_at(
+            Instant::now() + config.boot_node_refresh_interval,
+            config.boot_node_refresh_interval,
+        );
+        boot_node_refresh.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Ok(Self {
             inner,
             keypair: keypair.clone(),
             msg_interval: config.msg_interval,
             pending_events: Default::default(),
             pending_outbound_conns: Default::default(),
+            cancelled_dials: Default::default(),
+            provider_dials: Default::default(),
             ongoing_queries: Default::default(),
             outbound_conns: Default::default(),
             probe_timeouts: FuturesMap::new(config.probe_timeout, config.max_concurrent_probes),
-            registered_nodes: Arc::new(RwLock::new(Default::default())),
-        }
+            probe_start_times: Default::default(),
+            probe_queue: ProbeQueue::new(config.max_queued_probes),
+            max_concurrent_probes: config.max_concurrent_probes,
+            registered_nodes,
+            // The first tick completes immediately, which triggers the initial bootstrap
+            bootstrap_interval: interval(config.kad_bootstrap_interval),
+            min_routing_table_size: config.kad_min_routing_table_size,
+            dht_bootstrapped: false,
+            record_queries: Default::default(),
+            record_seq: 0,
+            provider_queries: Default::default(),
+            mdns_peers: Default::default(),
+            boot_nodes: boot_nodes
+                .into_iter()
+                .map(|node| (node.peer_id, node.address))
+                .collect(),
+            resolved_boot_nodes: Default::default(),
+            boot_node_resolver: None,
+            boot_node_refresh,
+            boot_node_lookup: None,
+            reachability: config
+                .reachability_interval
+                .filter(|interval| !interval.is_zero())
+                .map(ReachabilityMonitor::new),
+            latencies: Default::default(),
+            ping_max_failures: config.ping_max_failures,
+            relay_manager: None,
+            relay_manager_config: RelayManagerConfig {
+                reservations: config.relay_reservations,
+                retry_interval: config.relay_retry_interval,
+                auto: config.auto_relay,
+            },
+            peer_connections: Default::default(),
+            prefer_direct_connections: config.prefer_direct_connections,
+            hole_punch_stats: Default::default(),
+            external_addrs: ExternalAddrManager::new(config.external_addr_ttl),
+            address_policy: config.address_policy,
+        })
+    }
+
+    /// Hold reservations on some of the relays, always or, with `auto_relay`,
+    /// only while AutoNAT finds the node private.
+    pub fn set_relays(&mut self, relay_addrs: Vec<Multiaddr>) {
+        self.relay_manager = Some(RelayManager::new(relay_addrs, self.relay_manager_config));
+    }
+
+    /// State of the reservations on all candidate relays.
+    pub fn relay_reservations(&self) -> Vec<RelayReservation> {
+        self.relay_manager
+            .as_ref()
+            .map(|m| m.reservations())
+            .unwrap_or_default()
+    }
+
+    /// Periodically resolve boot nodes again, connecting to the ones which are new.
+    /// `resolved` are the boot nodes the resolver has given so far. Once it no longer lists
+    /// one of them, it's dropped. Boot nodes added in other ways are kept.
+    pub fn set_boot_node_resolver(&mut self, resolver: BootNodeResolver, resolved: Vec<BootNode>) {
+        self.boot_node_resolver = Some(resolver);
+        self.resolved_boot_nodes = resolved
+            .into_iter()
+            .map(|node| (node.peer_id, node.address))
+            .collect();
     }
 
     pub fn keypair(&self) -> &Keypair {
@@ -207,13 +545,13 @@ impl BaseBehaviour {
                 }
                 Ok(())
             });
-        self.inner.pubsub.subscribe(&topic, config);
+        self.inner.pubsub.subscribe(topic, config);
     }
 
     pub fn publish_message<T: Encode>(&mut self, topic: &'static str, msg: T) {
         let encoded_msg = msg.encode();
 
-        self.inner.pubsub.publish(&topic, encoded_msg);
+        self.inner.pubsub.publish(topic, encoded_msg);
     }
 
     pub fn find_and_dial(&mut self, peer_id: PeerId) {
@@ -226,86 +564,346 @@ impl BaseBehaviour {
         }
     }
 
+    /// Sign the value and store it in the DHT, expecting at least `quorum` peers to accept it.
+    pub fn put_record<T: Encode>(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: T,
+        quorum: Quorum,
+    ) -> Result<QueryId, kad::store::Error> {
+        // Milliseconds since the epoch, so that the sequence keeps increasing across restarts
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        self.record_seq = now.max(self.record_seq + 1);
+        let record = sign_record(
+            &self.keypair,
+            RecordKey::new(&key),
+            self.record_seq,
+            value.encode(),
+        );
+        self.inner.kademlia.put_record(record, quorum)
+    }
+
+    /// Look up a record in the DHT. Valid records are collected until `quorum` peers
+    /// returned one, then the newest of them is reported.
+    pub fn get_record(&mut self, key: impl AsRef<[u8]>, quorum: Quorum) -> QueryId {
+        let key = RecordKey::new(&key);
+        let query_id = self.inner.kademlia.get_record(key.clone());
+        self.record_queries.insert(
+            query_id,
+            RecordQuery {
+                key,
+                quorum: quorum_size(quorum),
+                found: 0,
+                newest: None,
+            },
+        );
+        query_id
+    }
+
+    /// Announce in the DHT that this node can provide the content under `key`.
+    pub fn start_providing(&mut self, key: impl AsRef<[u8]>) -> Result<QueryId, kad::store::Error> {
+        self.inner.kademlia.start_providing(RecordKey::new(&key))
+    }
+
+    /// Stop announcing the content. Provider records on other nodes expire eventually.
+    pub fn stop_providing(&mut self, key: impl AsRef<[u8]>) {
+        self.inner.kademlia.stop_providing(&RecordKey::new(&key));
+    }
+
+    /// Look up the peers providing the content under `key`. Providers are dialed as they're
+    /// found, which caches their addresses, so that they can be dialed by ID afterwards.
+    pub fn get_providers(&mut self, key: impl AsRef<[u8]>) -> QueryId {
+        let key = RecordKey::new(&key);
+        let query_id = self.inner.kademlia.get_providers(key.clone());
+        self.provider_queries
+            .insert(query_id, (key, HashSet::new()));
+        query_id
+    }
+
+    fn verify_record(&self, record: &kad::Record) -> Result<DhtRecord, InvalidRecord> {
+        verify_record(record, |peer_id| {
+            self.registered_nodes.read().contains(peer_id)
+        })
+    }
+
+    /// Bootstrap the DHT and look for a random peer to discover new peers.
+    fn refresh_dht(&mut self) {
+        if self.inner.kademlia.bootstrap().is_err() {
+            log::debug!("No known peers to bootstrap the DHT from");
+            return;
+        }
+        log::debug!("Bootstrapping DHT");
+        self.inner.kademlia.get_closest_peers(PeerId::random());
+    }
+
+    fn routing_table_size(&mut self) -> usize {
+        self.inner
+            .kademlia
+            .kbuckets()
+            .map(|bucket| bucket.num_entries())
+            .sum()
+    }
+
+    /// Number of connected authorities and the size of the authority set,
+    /// if authority connections are maintained.
+    pub fn authority_connectivity(&self) -> Option<(usize, usize)> {
+        self.inner.conn_manager.as_ref().map(|m| m.connectivity())
+    }
+
+    /// Whether the peer is connected directly or only through a relay.
+    pub fn connection_type(&self, peer_id: &PeerId) -> Option<ConnectionType> {
+        let connections = self.peer_connections.get(peer_id)?;
+        let types = || connections.values().map(|(conn_type, _)| *conn_type);
+        types()
+            .find(|t| *t == ConnectionType::Direct)
+            .or(types().next())
+    }
+
+    /// Number of successful and failed hole punches since startup.
+    pub fn hole_punch_stats(&self) -> HolePunchStats {
+        self.hole_punch_stats
+    }
+
+    /// Addresses under which peers observed the local node, and whether they're confirmed.
+    pub fn external_addr_candidates(&self) -> Vec<AddrCandidate> {
+        self.external_addrs.candidates()
+    }
+
+    /// Ping round-trip times and failures of a connected peer.
+    pub fn peer_latency(&self, peer_id: &PeerId) -> Option<&PeerLatency> {
+        self.latencies.get(peer_id)
+    }
+
+    /// Latest probe results of all registered authorities, if reachability is monitored.
+    pub fn reachability(&self) -> Option<&ReachabilityMatrix> {
+        self.reachability.as_ref().map(|m| m.matrix())
+    }
+
     pub fn outbound_conn_exists(&self, peer_id: &PeerId) -> bool {
         self.outbound_conns.get(peer_id).is_some_and(|x| *x > 0)
     }
 
-    fn try_schedule_probe(&mut self, peer_id: PeerId) -> Result<(), TryProbeError> {
-        if self.probe_timeouts.contains(peer_id) {
+    /// Whether a probe of the peer is queued or in progress.
+    pub fn is_probing(&self, peer_id: &PeerId) -> bool {
+        self.probe_timeouts.contains(*peer_id) || self.probe_queue.contains(peer_id)
+    }
+
+    fn schedule_probe(
+        &mut self,
+        peer_id: PeerId,
+        target: ProbeTarget,
+    ) -> Result<ProbeHandle, TryProbeError> {
+        if self.is_probing(&peer_id) {
             log::debug!("Probe for peer {peer_id} already ongoing");
             return Err(TryProbeError::Ongoing);
         }
-        if self
+        let priority = match self.registered_nodes.read().contains(&peer_id) {
+            true => ProbePriority::Authority,
+            false => ProbePriority::Normal,
+        };
+        // Only probes which have to wait for a slot count against the queue limit
+        if self.probe_queue.is_empty() && self.probe_timeouts.len() < self.max_concurrent_probes {
+            let handle = self.probe_queue.watch(peer_id);
+            self.start_probe(peer_id, target);
+            return Ok(handle);
+        }
+        let handle = self.probe_queue.push(peer_id, target, priority)?;
+        self.start_queued_probes();
+        Ok(handle)
+    }
+
+    /// Start queued probes while there are free slots.
+    fn start_queued_probes(&mut self) {
+        while self.probe_timeouts.len() < self.max_concurrent_probes {
+            let Some(QueuedProbe {
+                peer_id, target, ..
+            }) = self.probe_queue.pop()
+            else {
+                break;
+            };
+            if !self.start_probe(peer_id, target) {
+                break;
+            }
+        }
+    }
+
+    /// Start probing the peer in a free slot. Returns `false` if there is none.
+    fn start_probe(&mut self, peer_id: PeerId, target: ProbeTarget) -> bool {
+        // A finished probe of the same peer stays in the map until it's polled again,
+        // replacing it is fine
+        if let Err(PushError::BeyondCapacity(_)) = self
             .probe_timeouts
             .try_push(peer_id, futures::future::pending())
-            .is_err()
         {
-            log::debug!("Too  many ongoing probes");
-            return Err(TryProbeError::TooManyProbes);
-        }
-        if self.outbound_conn_exists(&peer_id) {
-            log::debug!("Closing outbound connection(s) to {peer_id}");
-            self.pending_events.push_back(ToSwarm::CloseConnection {
-                peer_id,
-                connection: Default::default(),
-            });
+            log::error!("No free slot for probe of {peer_id}");
+            return false;
         }
+        self.probe_start_times.insert(peer_id, Instant::now());
+        // Existing connections are left alone, the probe opens a connection of its own
         log::debug!("Probing peer {peer_id}");
-
-        Ok(())
+        match target {
+            ProbeTarget::Dht => self.find_and_dial(peer_id),
+            ProbeTarget::Direct(addrs) => {
+                let dial_opts = DialOpts::peer_id(peer_id)
+                    .addresses(addrs)
+                    .condition(PeerCondition::Always)
+                    .build();
+                let conn_id = dial_opts.connection_id();
+                self.pending_outbound_conns.insert(peer_id, conn_id);
+                self.pending_events
+                    .push_back(ToSwarm::Dial { opts: dial_opts });
+            }
+        }
+        true
     }
 
-    /// Try to find peer on DHT and connect
-    pub fn try_probe_dht(&mut self, peer_id: PeerId) -> Result<(), TryProbeError> {
-        self.try_schedule_probe(peer_id)?;
-        self.find_and_dial(peer_id);
-        Ok(())
+    /// Try to find peer on DHT and connect. If too many probes are ongoing, the probe
+    /// is queued, behind those of registered authorities.
+    pub fn try_probe_dht(&mut self, peer_id: PeerId) -> Result<ProbeHandle, TryProbeError> {
+        self.schedule_probe(peer_id, ProbeTarget::Dht)
     }
 
-    /// Try to connect to peer directly
+    /// Try to connect to peer directly. All addresses are dialed concurrently,
+    /// the first successful connection is used.
     pub fn try_probe_direct(
         &mut self,
         peer_id: PeerId,
-        addr: Multiaddr,
-    ) -> Result<(), TryProbeError> {
-        self.try_schedule_probe(peer_id)?;
-        let dial_opts = DialOpts::peer_id(peer_id)
-            .addresses(vec![addr])
-            .condition(PeerCondition::Always)
-            .build();
-        let conn_id = dial_opts.connection_id();
-        self.pending_outbound_conns.insert(peer_id, conn_id);
-        self.pending_events
-            .push_back(ToSwarm::Dial { opts: dial_opts });
-        Ok(())
+        addrs: impl IntoIterator<Item = Multiaddr>,
+    ) -> Result<ProbeHandle, TryProbeError> {
+        let addrs: Vec<_> = addrs.into_iter().collect();
+        if addrs.is_empty() {
+            return Err(TryProbeError::NoAddresses);
+        }
+        self.schedule_probe(peer_id, ProbeTarget::Direct(addrs))
+    }
+
+    /// Cancel a queued or ongoing probe. Its result is `ProbeErrorKind::Cancelled`.
+    /// Returns `false` if the peer wasn't being probed.
+    pub fn cancel_probe(&mut self, peer_id: PeerId) -> bool {
+        let queued = self.probe_queue.remove(&peer_id);
+        if !queued && !self.probe_timeouts.contains(peer_id) {
+            return false;
+        }
+        _ = self.probe_timeouts.remove(peer_id);
+        log::debug!("Probe for peer {peer_id} cancelled");
+        self.stop_probe(peer_id);
+        let ev = self.on_peer_probed(PeerProbed {
+            peer_id,
+            result: ProbeResult::Error {
+                kind: ProbeErrorKind::Cancelled,
+                message: "Probe cancelled".into(),
+            },
+        });
+        self.pending_events.push_back(ev);
+        true
     }
 
     fn on_dial_failure(
         &mut self,
         peer_id: PeerId,
         conn_id: ConnectionId,
-        error: String,
+        error: &DialError,
     ) -> Option<TToSwarm<Self>> {
+        self.cancelled_dials.remove(&conn_id);
+        self.provider_dials.remove(&conn_id);
         self.pending_outbound_conns.remove_by_right(&conn_id)?;
         log::debug!("Probe for peer {peer_id} failed: {error}");
 
         _ = self.probe_timeouts.remove(peer_id);
-        Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::PeerProbed(
-            PeerProbed {
-                peer_id,
-                result: ProbeResult::Error(error.into_boxed_str()),
+        self.probe_start_times.remove(&peer_id);
+        Some(self.on_peer_probed(PeerProbed {
+            peer_id,
+            result: ProbeResult::Error {
+                kind: error.into(),
+                message: error.to_string().into_boxed_str(),
             },
-        )))
+        }))
+    }
+
+    /// Stop the DHT lookup and the dial of a probe which won't be completed. A dial which
+    /// connects anyway is closed.
+    fn stop_probe(&mut self, peer_id: PeerId) {
+        if let Some((_, query_id)) = self.ongoing_queries.remove_by_left(&peer_id) {
+            if let Some(mut query) = self.inner.kademlia.query_mut(&query_id) {
+                query.finish();
+            }
+        }
+        if let Some((_, conn_id)) = self.pending_outbound_conns.remove_by_left(&peer_id) {
+            let connected = self
+                .peer_connections
+                .get(&peer_id)
+                .is_some_and(|c| c.contains_key(&conn_id));
+            if connected {
+                self.pending_events.push_back(ToSwarm::CloseConnection {
+                    peer_id,
+                    connection: CloseConnection::One(conn_id),
+                });
+            } else {
+                self.cancelled_dials.insert(conn_id);
+            }
+        }
+        self.probe_start_times.remove(&peer_id);
     }
 
     fn on_probe_timeout(&mut self, peer_id: PeerId) -> TToSwarm<Self> {
         log::debug!("Probe for peer {peer_id} timed out");
 
-        self.pending_outbound_conns.remove_by_left(&peer_id);
-        ToSwarm::GenerateEvent(BaseBehaviourEvent::PeerProbed(PeerProbed {
+        self.stop_probe(peer_id);
+        self.on_peer_probed(PeerProbed {
             peer_id,
-            result: ProbeResult::Timeout,
-        }))
+            result: ProbeResult::Error {
+                kind: ProbeErrorKind::Timeout,
+                message: "Probe timed out".into(),
+            },
+        })
+    }
+
+    /// Pass the probe result to its handle and record it, if reachability of the peer is monitored.
+    fn on_peer_probed(&mut self, probed: PeerProbed) -> TToSwarm<Self> {
+        self.probe_queue.complete(&probed.peer_id, &probed.result);
+        if let Some(recorded) = self
+            .reachability
+            .as_mut()
+            .and_then(|m| m.on_probe_result(probed.peer_id, &probed.result))
+        {
+            if recorded.changed {
+                let reachable = matches!(probed.result, ProbeResult::Reachable { .. });
+                self.pending_events.push_back(ToSwarm::GenerateEvent(
+                    BaseBehaviourEvent::ReachabilityChanged {
+                        peer_id: probed.peer_id,
+                        reachable,
+                    },
+                ));
+            }
+            if recorded.round_finished {
+                let matrix = self.reachability().cloned().unwrap_or_default();
+                self.pending_events.push_back(ToSwarm::GenerateEvent(
+                    BaseBehaviourEvent::ReachabilityUpdated(matrix),
+                ));
+            }
+        }
+        ToSwarm::GenerateEvent(BaseBehaviourEvent::PeerProbed(probed))
+    }
+
+    /// Queue probes of the authorities in the current reachability round.
+    fn schedule_reachability_probes(&mut self) {
+        while let Some(peer_id) = self.reachability.as_mut().and_then(|m| m.next_peer()) {
+            // Probes started by the app are recorded all the same
+            if !self.is_probing(&peer_id) {
+                if let Err(e) = self.schedule_probe(peer_id, ProbeTarget::Dht) {
+                    log::debug!("Cannot probe authority {peer_id} yet: {e}");
+                    if let Some(monitor) = self.reachability.as_mut() {
+                        monitor.requeue(peer_id);
+                    }
+                    break;
+                }
+            }
+            if let Some(monitor) = self.reachability.as_mut() {
+                monitor.probe_started(peer_id);
+            }
+        }
     }
 
     pub fn allow_peer(&mut self, peer_id: PeerId) {
@@ -317,6 +915,60 @@ impl BaseBehaviour {
 pub enum BaseBehaviourEvent {
     PeerProbed(PeerProbed),
     Gossipsub(GossipSubMessage),
+    /// The routing table reached `kad_min_routing_table_size` for the first time.
+    DhtBootstrapped {
+        routing_table_size: usize,
+    },
+    RecordStored {
+        query_id: QueryId,
+        key: RecordKey,
+        result: Result<(), RecordError>,
+    },
+    RecordFound {
+        query_id: QueryId,
+        key: RecordKey,
+        result: Result<DhtRecord, RecordError>,
+    },
+    ProvidingStarted {
+        query_id: QueryId,
+        key: RecordKey,
+        result: Result<(), RecordError>,
+    },
+    ProvidersFound {
+        query_id: QueryId,
+        key: RecordKey,
+        result: Result<HashSet<PeerId>, RecordError>,
+    },
+    /// The number of connected authorities or the size of the authority set changed.
+    AuthorityConnectivity {
+        connected: usize,
+        total: usize,
+    },
+    /// A monitored authority was probed for the first time, or its reachability changed.
+    ReachabilityChanged {
+        peer_id: PeerId,
+        reachable: bool,
+    },
+    /// A round of probing all registered authorities finished.
+    ReachabilityUpdated(ReachabilityMatrix),
+    /// AutoNAT determined whether the node is publicly reachable.
+    NatStatusChanged {
+        old: NatStatus,
+        new: NatStatus,
+    },
+    /// Hole punching through a relayed connection finished. On success,
+    /// the ID of the direct connection is given.
+    HolePunched {
+        peer_id: PeerId,
+        result: Result<ConnectionId, Box<str>>,
+    },
+    /// A reservation on a relay was requested, accepted, or lost.
+    RelayReservationChanged(RelayReservation),
+    /// A peer was pinged, successfully or not.
+    PeerLatency {
+        peer_id: PeerId,
+        latency: PeerLatency,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -334,22 +986,105 @@ pub struct PeerProbed {
 
 #[derive(Debug, Clone)]
 pub enum ProbeResult {
-    Timeout,
-    Error(Box<str>),
+    Error {
+        kind: ProbeErrorKind,
+        message: Box<str>,
+    },
     Reachable {
         listen_addrs: Vec<Multiaddr>,
         agent_version: Box<str>,
+        /// Protocols the peer supports, as reported by identify
+        protocols: Vec<StreamProtocol>,
+        /// Local address as observed by the peer
+        observed_addr: Multiaddr,
+        /// Address the probe connected to
+        addr: Multiaddr,
+        connection_type: ConnectionType,
+        /// Time from starting the probe until the peer identified itself, including the DHT
+        /// lookup and connection setup. Not a round-trip time, see `BaseBehaviour::peer_latency` for that.
+        elapsed: Duration,
     },
 }
 
+/// Why a probe failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeErrorKind {
+    /// Connecting failed on all addresses
+    Transport,
+    /// The connection was denied locally, e.g. by the whitelist or connection limits
+    Denied,
+    Timeout,
+    /// A different peer was reached on the address
+    WrongPeerId,
+    /// No address of the peer is known
+    NoAddresses,
+    Cancelled,
+    Other,
+}
+
+impl From<&DialError> for ProbeErrorKind {
+    fn from(error: &DialError) -> Self {
+        match error {
+            DialError::Transport(_) => Self::Transport,
+            DialError::Denied { .. } => Self::Denied,
+            DialError::WrongPeerId { .. } => Self::WrongPeerId,
+            DialError::NoAddresses => Self::NoAddresses,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionType {
+    Direct,
+    Relayed,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HolePunchStats {
+    pub successes: u64,
+    pub failures: u64,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum TryProbeError {
-    #[error("There are too many active probes")]
+    #[error("There are too many queued probes")]
     TooManyProbes,
+    #[error("No addresses to probe")]
+    NoAddresses,
     #[error("There is already an ongoing probe for this peer")]
     Ongoing,
 }
 
+/// Ongoing record lookup.
+struct RecordQuery {
+    key: RecordKey,
+    /// Number of valid records needed
+    quorum: usize,
+    found: usize,
+    newest: Option<DhtRecord>,
+}
+
+/// The replication factor is left at Kademlia's default.
+fn quorum_size(quorum: Quorum) -> usize {
+    match quorum {
+        Quorum::One => 1,
+        Quorum::Majority => kad::K_VALUE.get() / 2 + 1,
+        Quorum::All => kad::K_VALUE.get(),
+        Quorum::N(n) => n.get(),
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum RecordError {
+    #[error("No valid record found")]
+    NotFound,
+    #[error("Quorum not reached, {success} of {quorum} peers succeeded")]
+    QuorumFailed { success: usize, quorum: usize },
+    #[error("DHT query timed out")]
+    Timeout,
+}
+
 impl BehaviourWrapper for BaseBehaviour {
     type Inner = InnerBehaviour;
     type Event = BaseBehaviourEvent;
@@ -359,6 +1094,12 @@ impl BehaviourWrapper for BaseBehaviour {
     }
 
     fn on_swarm_event(&mut self, ev: FromSwarm) -> impl IntoIterator<Item = TToSwarm<Self>> {
+        if self.external_addrs.on_swarm_event(&ev) {
+            // Let connected peers know right away, instead of at the next identify interval
+            self.inner
+                .identify
+                .push(self.peer_connections.keys().copied());
+        }
         match ev {
             FromSwarm::ConnectionEstablished(conn) => self.on_connection_established(conn),
             FromSwarm::ConnectionClosed(conn) => self.on_connection_closed(conn),
@@ -366,7 +1107,34 @@ impl BehaviourWrapper for BaseBehaviour {
                 peer_id: Some(peer_id),
                 error,
                 connection_id,
-            }) => self.on_dial_failure(peer_id, connection_id, error.to_string()),
+            }) => self.on_dial_failure(peer_id, connection_id, error),
+            FromSwarm::ExternalAddrConfirmed(e) => {
+                if let Some(manager) = self.relay_manager.as_mut() {
+                    manager.on_external_addr_confirmed(e.addr);
+                }
+                None
+            }
+            FromSwarm::ExternalAddrExpired(e) => {
+                if let Some(manager) = self.relay_manager.as_mut() {
+                    manager.on_external_addr_expired(e.addr);
+                }
+                None
+            }
+            FromSwarm::ListenerClosed(e) => {
+                if let Some(manager) = self.relay_manager.as_mut() {
+                    let reason = match e.reason {
+                        Ok(()) => "Listener closed".to_string(),
+                        Err(err) => err.to_string(),
+                    };
+                    manager.on_listener_failed(e.listener_id, &reason);
+                }
+                None
+            }
+            // Non-fatal, the listener keeps running. A failed reservation closes the listener.
+            FromSwarm::ListenerError(e) => {
+                log::debug!("Listener {:?} error: {}", e.listener_id, e.err);
+                None
+            }
             _ => None,
         }
     }
@@ -380,34 +1148,228 @@ impl BehaviourWrapper for BaseBehaviour {
             InnerBehaviourEvent::Kademlia(ev) => self.on_kademlia_event(ev),
             InnerBehaviourEvent::Autonat(ev) => self.on_autonat_event(ev),
             InnerBehaviourEvent::Pubsub(ev) => self.on_pubsub_event(ev),
-            InnerBehaviourEvent::Ping(_ev) => None,
-            InnerBehaviourEvent::Dcutr(_ev) => None,
+            InnerBehaviourEvent::Ping(ev) => self.on_ping_event(ev),
+            InnerBehaviourEvent::Dcutr(ev) => self.on_dcutr_event(ev),
+            InnerBehaviourEvent::Relay(ev) => self.on_relay_client_event(ev),
+            InnerBehaviourEvent::RelayServer(ev) => self.on_relay_server_event(ev),
             InnerBehaviourEvent::Whitelist(nodes) => self.on_nodes_update(nodes),
+            InnerBehaviourEvent::Mdns(ev) => self.on_mdns_event(ev),
+            InnerBehaviourEvent::ConnManager(ev) => self.on_conn_manager_event(ev),
             _ => None,
         }
     }
 
     fn poll(&mut self, cx: &mut Context<'_>) -> Poll<impl IntoIterator<Item = TToSwarm<Self>>> {
-        loop {
+        if let Some(ev) = self.pending_events.pop_front() {
+            return Poll::Ready(Some(ev));
+        }
+
+        match self.probe_timeouts.poll_unpin(cx) {
+            Poll::Ready((peer_id, Err(_))) => {
+                return Poll::Ready(Some(self.on_probe_timeout(peer_id)));
+            }
+            Poll::Pending => {}
+            _ => unreachable!(), // future::pending() should never complete
+        }
+
+        // Slots of finished probes are only freed by polling `probe_timeouts`
+        if !self.probe_queue.is_empty() {
+            self.start_queued_probes();
+            if let Some(ev) = self.pending_events.pop_front() {
+                return Poll::Ready(Some(ev));
+            }
+        }
+
+        if poll_ticks(&mut self.bootstrap_interval, cx) {
+            self.refresh_dht();
+        }
+
+        if let Some(monitor) = self.reachability.as_mut() {
+            let local_peer_id = self.keypair.public().to_peer_id();
+            monitor.poll_round(cx, &self.registered_nodes.read(), local_peer_id);
+            self.schedule_reachability_probes();
             if let Some(ev) = self.pending_events.pop_front() {
                 return Poll::Ready(Some(ev));
             }
+        }
 
-            match self.probe_timeouts.poll_unpin(cx) {
-                Poll::Ready((peer_id, Err(_))) => {
-                    return Poll::Ready(Some(self.on_probe_timeout(peer_id)));
+        if let Poll::Ready(ev) = self.external_addrs.poll(cx) {
+            let ev = ev
+                .map_in(|never| match never {})
+                .map_out(|never| match never {});
+            return Poll::Ready(Some(ev));
+        }
+
+        if let Some(manager) = self.relay_manager.as_mut() {
+            let latencies = &self.latencies;
+            let latency = |peer_id: &PeerId| latencies.get(peer_id)?.rtt.map(|rtt| rtt.ewma);
+            if let Poll::Ready(ev) = manager.poll(cx, latency) {
+                let ev = ev
+                    .map_in(|never| match never {})
+                    .map_out(BaseBehaviourEvent::RelayReservationChanged);
+                return Poll::Ready(Some(ev));
+            }
+        }
+
+        if let Some(resolver) = &self.boot_node_resolver {
+            // Ticks during a lookup are skipped
+            if poll_ticks(&mut self.boot_node_refresh, cx) && self.boot_node_lookup.is_none() {
+                let resolver = resolver.clone();
+                self.boot_node_lookup = Some(async move { resolver.resolve_all().await }.boxed());
+            }
+        }
+        if let Some(lookup) = &mut self.boot_node_lookup {
+            if let Poll::Ready(resolved) = lookup.poll_unpin(cx) {
+                self.boot_node_lookup = None;
+                self.on_boot_nodes_resolved(resolved);
+                if let Some(ev) = self.pending_events.pop_front() {
+                    return Poll::Ready(Some(ev));
                 }
-                Poll::Pending => {}
-                _ => unreachable!(), // future::pending() should never complete
             }
+        }
 
-            return Poll::Pending;
+        Poll::Pending
+    }
+
+    fn filter_dial_addresses(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: Vec<Multiaddr>,
+    ) -> Vec<Multiaddr> {
+        let Some(peer_id) = maybe_peer else {
+            return addresses;
+        };
+        // Kademlia also offers addresses from other peers' query responses
+        let addresses: Vec<_> = addresses
+            .into_iter()
+            .filter(|addr| self.allows_addr(&peer_id, addr))
+            .collect();
+        if self.provider_dials.remove(&connection_id) {
+            let cache = &mut self.inner.address_cache;
+            let new_addrs: Vec<_> = addresses
+                .iter()
+                .filter(|addr| cache.addr_info(&peer_id, addr).is_none())
+                .cloned()
+                .collect();
+            if !new_addrs.is_empty() {
+                cache.put(peer_id, new_addrs, AddrSource::Dht);
+            }
         }
+        addresses
+    }
+}
+
+impl InnerBehaviour {
+    fn add_boot_node(&mut self, boot_node: &BootNode) {
+        self.whitelist.allow_peer(boot_node.peer_id);
+        self.address_cache.put(
+            boot_node.peer_id,
+            [boot_node.address.clone()],
+            AddrSource::Config,
+        );
+        self.kademlia
+            .add_address(&boot_node.peer_id, boot_node.address.clone());
+        self.autonat
+            .add_server(boot_node.peer_id, Some(boot_node.address.clone()));
     }
 }
 
 impl BaseBehaviour {
+    /// Addresses in the cache are either trusted or were already checked against the policy.
+    fn allows_addr(&self, peer_id: &PeerId, addr: &Multiaddr) -> bool {
+        self.address_policy.allows(addr)
+            || self.inner.address_cache.addr_info(peer_id, addr).is_some()
+    }
+
+    fn on_boot_nodes_resolved(&mut self, resolved: ResolvedBootNodes) {
+        let listed: HashSet<_> = resolved
+            .boot_nodes
+            .iter()
+            .map(|node| (node.peer_id, node.address.clone()))
+            .collect();
+        // A failed lookup doesn't mean the boot nodes are gone
+        if resolved.complete {
+            let removed: Vec<_> = self
+                .resolved_boot_nodes
+                .difference(&listed)
+                .cloned()
+                .collect();
+            for (peer_id, address) in removed {
+                self.remove_boot_node(peer_id, address);
+            }
+            self.resolved_boot_nodes = listed;
+        } else {
+            self.resolved_boot_nodes.extend(listed);
+        }
+
+        for boot_node in resolved.boot_nodes {
+            if !self
+                .boot_nodes
+                .insert((boot_node.peer_id, boot_node.address.clone()))
+            {
+                continue;
+            }
+            log::info!(
+                "Connecting to new boot node {} at {}",
+                boot_node.peer_id,
+                boot_node.address
+            );
+            self.inner.add_boot_node(&boot_node);
+            self.pending_events.push_back(ToSwarm::Dial {
+                opts: DialOpts::peer_id(boot_node.peer_id)
+                    .addresses(vec![boot_node.address])
+                    .condition(PeerCondition::DisconnectedAndNotDialing)
+                    .build(),
+            });
+        }
+    }
+
+    fn remove_boot_node(&mut self, peer_id: PeerId, address: Multiaddr) {
+        log::info!("Boot node {peer_id} at {address} removed");
+        self.boot_nodes.remove(&(peer_id, address.clone()));
+        self.inner
+            .address_cache
+            .remove(peer_id, &address, AddrSource::Config);
+        self.inner.kademlia.remove_address(&peer_id, &address);
+        if self.boot_nodes.iter().any(|(p, _)| *p == peer_id) {
+            return;
+        }
+        self.inner.autonat.remove_server(&peer_id);
+        // Authorities are allowed to connect regardless
+        if !self.registered_nodes.read().contains(&peer_id) {
+            self.inner.whitelist.disallow_peer(peer_id);
+        }
+    }
+
     fn on_connection_established(&mut self, conn: ConnectionEstablished) -> Option<TToSwarm<Self>> {
+        if self.cancelled_dials.remove(&conn.connection_id) {
+            log::debug!("Closing connection to {} of cancelled probe", conn.peer_id);
+            self.pending_events.push_back(ToSwarm::CloseConnection {
+                peer_id: conn.peer_id,
+                connection: CloseConnection::One(conn.connection_id),
+            });
+        }
+        let conn_type = match conn.endpoint.is_relayed() {
+            true => ConnectionType::Relayed,
+            false => ConnectionType::Direct,
+        };
+        let connections = self.peer_connections.entry(conn.peer_id).or_default();
+        let remote_addr = conn.endpoint.get_remote_address().clone();
+        connections.insert(conn.connection_id, (conn_type, remote_addr));
+        if conn_type == ConnectionType::Direct && self.prefer_direct_connections {
+            for (conn_id, _) in connections
+                .iter()
+                .filter(|(_, (t, _))| *t == ConnectionType::Relayed)
+            {
+                log::debug!("Direct connection to {}, closing relayed one", conn.peer_id);
+                self.pending_events.push_back(ToSwarm::CloseConnection {
+                    peer_id: conn.peer_id,
+                    connection: CloseConnection::One(*conn_id),
+                });
+            }
+        }
+
         let peer_id = match conn.endpoint {
             ConnectedPoint::Dialer { .. } => conn.peer_id,
             _ => return None,
@@ -418,6 +1380,15 @@ impl BaseBehaviour {
     }
 
     fn on_connection_closed(&mut self, conn: ConnectionClosed) -> Option<TToSwarm<Self>> {
+        if conn.remaining_established == 0 {
+            if let Some(manager) = self.relay_manager.as_mut() {
+                manager.on_disconnected(conn.peer_id);
+            }
+            self.latencies.remove(&conn.peer_id);
+            self.peer_connections.remove(&conn.peer_id);
+        } else if let Some(connections) = self.peer_connections.get_mut(&conn.peer_id) {
+            connections.remove(&conn.connection_id);
+        }
         let peer_id = match conn.endpoint {
             ConnectedPoint::Dialer { .. } => conn.peer_id,
             _ => return None,
@@ -433,46 +1404,65 @@ impl BaseBehaviour {
     fn on_identify_event(&mut self, ev: identify::Event) -> Option<TToSwarm<Self>> {
         log::debug!("Identify event received: {ev:?}");
 
-        let (peer_id, listen_addrs, agent_version, conn_id) = match ev {
-            identify::Event::Received {
-                peer_id,
-                info,
-                connection_id,
-            } => (
-                peer_id,
-                info.listen_addrs,
-                info.agent_version,
-                connection_id,
-            ),
-            _ => return None,
+        let identify::Event::Received {
+            peer_id,
+            info,
+            connection_id: conn_id,
+        } = ev
+        else {
+            return None;
         };
+        let listen_addrs = info.listen_addrs;
 
-        // Filter out unreachable (private) addresses and add the remaining to cache and DHT
-        let listen_addrs = listen_addrs.into_iter().filter(addr_is_reachable);
-        self.inner.address_cache.put(peer_id, listen_addrs.clone());
-        listen_addrs.clone().for_each(|addr| {
-            self.inner.kademlia.add_address(&peer_id, addr);
-        });
+        // Filter out addresses denied by the policy and add the remaining to cache and DHT
+        let listen_addrs: Vec<_> = listen_addrs
+            .into_iter()
+            .filter(|addr| self.address_policy.allows(addr))
+            .collect();
+        self.inner
+            .address_cache
+            .put(peer_id, listen_addrs.clone(), AddrSource::Identify);
+        for addr in &listen_addrs {
+            self.inner.kademlia.add_address(&peer_id, addr.clone());
+        }
 
-        let pending_conn = self.pending_outbound_conns.get_by_left(&peer_id);
-        // In case of a DHT probe, there should be no connection ID in `pending_outbound_conns`
-        // In case of a direct probe, the connection ID should match the one stored
-        if self.probe_timeouts.contains(peer_id)
-            && (pending_conn.is_none() || pending_conn.is_some_and(|id| id == &conn_id))
-        {
+        // Only the connection dialed by the probe counts, not existing ones
+        let probe_conn = self.pending_outbound_conns.get_by_left(&peer_id) == Some(&conn_id);
+        if self.probe_timeouts.contains(peer_id) && probe_conn {
+            let connections = self.peer_connections.get(&peer_id);
+            let Some((connection_type, addr)) = connections.and_then(|c| c.get(&conn_id)).cloned()
+            else {
+                log::warn!("Identify received on unknown connection {conn_id} to {peer_id}");
+                return None;
+            };
+            if connections.is_some_and(|c| c.len() > 1) {
+                // The peer was connected before, the extra connection isn't needed anymore
+                self.pending_events.push_back(ToSwarm::CloseConnection {
+                    peer_id,
+                    connection: CloseConnection::One(conn_id),
+                });
+            }
             self.probe_timeouts.remove(peer_id);
             self.pending_outbound_conns.remove_by_left(&peer_id);
+            let elapsed = self
+                .probe_start_times
+                .remove(&peer_id)
+                .map(|start| start.elapsed())
+                .unwrap_or_default();
 
             log::debug!("Probe for {peer_id} succeeded");
-            Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::PeerProbed(
-                PeerProbed {
-                    peer_id,
-                    result: ProbeResult::Reachable {
-                        listen_addrs: listen_addrs.collect(),
-                        agent_version: agent_version.into_boxed_str(),
-                    },
+            Some(self.on_peer_probed(PeerProbed {
+                peer_id,
+                result: ProbeResult::Reachable {
+                    listen_addrs,
+                    agent_version: info.agent_version.into_boxed_str(),
+                    protocols: info.protocols,
+                    observed_addr: info.observed_addr,
+                    addr,
+                    connection_type,
+                    elapsed,
                 },
-            )))
+            }))
         } else {
             None
         }
@@ -481,16 +1471,275 @@ impl BaseBehaviour {
     fn on_kademlia_event(&mut self, ev: kad::Event) -> Option<TToSwarm<Self>> {
         log::debug!("Kademlia event received: {ev:?}");
 
-        let kad::Event::OutboundQueryProgressed {
-            id: query_id,
-            result: QueryResult::GetClosestPeers(result),
-            step: ProgressStep { last, .. },
-            ..
-        } = ev
-        else {
+        match ev {
+            kad::Event::OutboundQueryProgressed {
+                id: query_id,
+                result: QueryResult::GetClosestPeers(result),
+                step: ProgressStep { last, .. },
+                ..
+            } => self.on_closest_peers(query_id, result, last),
+            kad::Event::OutboundQueryProgressed {
+                id: query_id,
+                result: QueryResult::PutRecord(result),
+                ..
+            } => self.on_record_stored(query_id, result),
+            kad::Event::OutboundQueryProgressed {
+                id: query_id,
+                result: QueryResult::GetRecord(result),
+                step: ProgressStep { last, .. },
+                ..
+            } => self.on_get_record_progress(query_id, result, last),
+            kad::Event::OutboundQueryProgressed {
+                id: query_id,
+                result: QueryResult::StartProviding(result),
+                ..
+            } => self.on_providing_started(query_id, result),
+            kad::Event::OutboundQueryProgressed {
+                id: query_id,
+                result: QueryResult::GetProviders(result),
+                step: ProgressStep { last, .. },
+                ..
+            } => self.on_get_providers_progress(query_id, result, last),
+            kad::Event::InboundRequest { request } => {
+                self.on_inbound_kad_request(request);
+                None
+            }
+            kad::Event::RoutingUpdated {
+                peer, addresses, ..
+            } => {
+                let denied: Vec<_> = addresses
+                    .iter()
+                    .filter(|addr| !self.allows_addr(&peer, addr))
+                    .cloned()
+                    .collect();
+                for addr in denied {
+                    log::trace!("Removing address {addr} of {peer} from DHT, denied by policy");
+                    self.inner.kademlia.remove_address(&peer, &addr);
+                }
+                self.check_dht_bootstrapped()
+            }
+            _ => None,
+        }
+    }
+
+    fn check_dht_bootstrapped(&mut self) -> Option<TToSwarm<Self>> {
+        if self.dht_bootstrapped {
+            return None;
+        }
+        let routing_table_size = self.routing_table_size();
+        if routing_table_size < self.min_routing_table_size {
+            return None;
+        }
+        log::info!("DHT bootstrapped with {routing_table_size} peers in routing table");
+        self.dht_bootstrapped = true;
+        Some(ToSwarm::GenerateEvent(
+            BaseBehaviourEvent::DhtBootstrapped { routing_table_size },
+        ))
+    }
+
+    fn on_record_stored(
+        &mut self,
+        query_id: QueryId,
+        result: kad::PutRecordResult,
+    ) -> Option<TToSwarm<Self>> {
+        let (key, result) = match result {
+            Ok(kad::PutRecordOk { key }) => (key, Ok(())),
+            Err(PutRecordError::QuorumFailed {
+                key,
+                success,
+                quorum,
+            }) => {
+                let error = RecordError::QuorumFailed {
+                    success: success.len(),
+                    quorum: quorum.get(),
+                };
+                (key, Err(error))
+            }
+            Err(PutRecordError::Timeout { key, .. }) => (key, Err(RecordError::Timeout)),
+        };
+        if let Err(e) = &result {
+            log::warn!("Storing DHT record {key:?} failed: {e}");
+        }
+        Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::RecordStored {
+            query_id,
+            key,
+            result,
+        }))
+    }
+
+    fn on_get_record_progress(
+        &mut self,
+        query_id: QueryId,
+        result: kad::GetRecordResult,
+        last: bool,
+    ) -> Option<TToSwarm<Self>> {
+        if !self.record_queries.contains_key(&query_id) {
+            return None;
+        }
+        let mut timed_out = false;
+        let verified = match result {
+            Ok(GetRecordOk::FoundRecord(kad::PeerRecord { peer, record })) => self
+                .verify_record(&record)
+                .map_err(|e| log::debug!("Invalid DHT record from {peer:?}: {e}"))
+                .ok(),
+            Ok(GetRecordOk::FinishedWithNoAdditionalRecord { .. }) => None,
+            Err(kad::GetRecordError::Timeout { .. }) => {
+                timed_out = true;
+                None
+            }
+            Err(_) => None,
+        };
+        let query = self.record_queries.get_mut(&query_id)?;
+        if let Some(record) = verified {
+            query.found += 1;
+            if query
+                .newest
+                .as_ref()
+                .is_none_or(|newest| check_newer(&record, newest).is_ok())
+            {
+                query.newest = Some(record);
+            }
+        }
+        // Keep looking until the quorum is met
+        let quorum_met = query.found >= query.quorum;
+        if !quorum_met && !last {
+            return None;
+        }
+        let query = self.record_queries.remove(&query_id)?;
+        if let Some(mut kad_query) = self.inner.kademlia.query_mut(&query_id) {
+            kad_query.finish();
+        }
+        let result = match query.newest {
+            Some(record) if quorum_met => Ok(record),
+            Some(_) => Err(RecordError::QuorumFailed {
+                success: query.found,
+                quorum: query.quorum,
+            }),
+            None if timed_out => Err(RecordError::Timeout),
+            None => Err(RecordError::NotFound),
+        };
+        Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::RecordFound {
+            query_id,
+            key: query.key,
+            result,
+        }))
+    }
+
+    fn on_providing_started(
+        &mut self,
+        query_id: QueryId,
+        result: kad::AddProviderResult,
+    ) -> Option<TToSwarm<Self>> {
+        let (key, result) = match result {
+            Ok(kad::AddProviderOk { key }) => (key, Ok(())),
+            Err(AddProviderError::Timeout { key }) => (key, Err(RecordError::Timeout)),
+        };
+        if let Err(e) = &result {
+            log::warn!("Announcing provider record {key:?} failed: {e}");
+        }
+        Some(ToSwarm::GenerateEvent(
+            BaseBehaviourEvent::ProvidingStarted {
+                query_id,
+                key,
+                result,
+            },
+        ))
+    }
+
+    fn on_get_providers_progress(
+        &mut self,
+        query_id: QueryId,
+        result: kad::GetProvidersResult,
+        last: bool,
+    ) -> Option<TToSwarm<Self>> {
+        if !self.provider_queries.contains_key(&query_id) {
             return None;
+        }
+        let local_peer_id = self.keypair.public().to_peer_id();
+        let timed_out = match result {
+            Ok(GetProvidersOk::FoundProviders { providers, .. }) => {
+                let (_, found) = self.provider_queries.get_mut(&query_id)?;
+                for peer_id in providers {
+                    if peer_id == local_peer_id || !found.insert(peer_id) {
+                        continue;
+                    }
+                    // Kademlia only knows the addresses until the query finishes,
+                    // they're offered when dialing
+                    let opts = DialOpts::peer_id(peer_id).build();
+                    self.provider_dials.insert(opts.connection_id());
+                    self.pending_events.push_back(ToSwarm::Dial { opts });
+                }
+                false
+            }
+            Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => false,
+            Err(GetProvidersError::Timeout { .. }) => true,
         };
+        if !last {
+            return None;
+        }
+        let (key, providers) = self.provider_queries.remove(&query_id)?;
+        let result = match (providers.is_empty(), timed_out) {
+            (false, _) => Ok(providers),
+            (true, false) => Err(RecordError::NotFound),
+            (true, true) => Err(RecordError::Timeout),
+        };
+        Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::ProvidersFound {
+            query_id,
+            key,
+            result,
+        }))
+    }
+
+    fn on_inbound_kad_request(&mut self, request: InboundRequest) {
+        match request {
+            InboundRequest::PutRecord {
+                source,
+                record: Some(record),
+                ..
+            } => {
+                let verified = match self.verify_record(&record) {
+                    Ok(verified) => verified,
+                    Err(e) => {
+                        log::debug!("Rejecting DHT record from {source}: {e}");
+                        return;
+                    }
+                };
+                let store = self.inner.kademlia.store_mut();
+                if let Some(Err(e)) = store
+                    .get(&record.key)
+                    .map(|stored| check_replay(&verified, &stored))
+                {
+                    log::debug!("Rejecting DHT record from {source}: {e}");
+                    return;
+                }
+                if let Err(e) = store.put(record) {
+                    log::warn!("Storing DHT record from {source} failed: {e}");
+                }
+            }
+            InboundRequest::AddProvider {
+                record: Some(record),
+            } => {
+                if !self.registered_nodes.read().contains(&record.provider) {
+                    log::debug!(
+                        "Rejecting provider record from non-authority {}",
+                        record.provider
+                    );
+                    return;
+                }
+                if let Err(e) = self.inner.kademlia.store_mut().add_provider(record) {
+                    log::warn!("Storing provider record failed: {e}");
+                }
+            }
+            _ => {}
+        }
+    }
 
+    fn on_closest_peers(
+        &mut self,
+        query_id: QueryId,
+        result: kad::GetClosestPeersResult,
+        last: bool,
+    ) -> Option<TToSwarm<Self>> {
         let peer_id = self.ongoing_queries.get_by_right(&query_id)?.to_owned();
         let peer_info = match result {
             Ok(GetClosestPeersOk { peers, .. })
@@ -509,30 +1758,78 @@ impl BaseBehaviour {
         if let Some(peer_info) = peer_info {
             // Cache the found address(es) so they can be used for dialing
             // (kademlia might not do it by itself, if the bucket is full)
-            self.inner.address_cache.put(peer_id, peer_info.addrs);
+            self.inner
+                .address_cache
+                .put(peer_id, peer_info.addrs, AddrSource::Dht);
         }
 
+        if !query_finished {
+            return None;
+        }
         // Try to dial even if `peer_info` is `None`.
         // There might be some address(es) cached from previous queries.
-        query_finished.then_some(ToSwarm::Dial {
-            // Not using the default condition (`DisconnectedAndNotDialing`), because we may want
-            // to establish an outbound connection to the peer despite existing inbound connection.
-            opts: DialOpts::peer_id(peer_id)
-                .condition(PeerCondition::NotDialing)
-                .build(),
-        })
+        let probing = self.probe_timeouts.contains(peer_id)
+            && !self.pending_outbound_conns.contains_left(&peer_id);
+        // Not using the default condition (`DisconnectedAndNotDialing`), because we may want
+        // to establish an outbound connection to the peer despite existing inbound connection.
+        // A probe always gets a connection of its own, so that it's not answered by an old one.
+        let condition = match probing {
+            true => PeerCondition::Always,
+            false => PeerCondition::NotDialing,
+        };
+        let opts = DialOpts::peer_id(peer_id).condition(condition).build();
+        if probing {
+            self.pending_outbound_conns
+                .insert(peer_id, opts.connection_id());
+        }
+        Some(ToSwarm::Dial { opts })
     }
 
     fn on_autonat_event(&mut self, ev: autonat::Event) -> Option<TToSwarm<Self>> {
         log::debug!("AutoNAT event received: {ev:?}");
-        let autonat::Event::StatusChanged { new: status, .. } = ev else {
-            return None;
+        let (old, new) = match ev {
+            autonat::Event::StatusChanged { old, new } => (old, new),
+            autonat::Event::OutboundProbe(autonat::OutboundProbeEvent::Response {
+                address,
+                ..
+            }) => {
+                self.external_addrs.on_confirmed(address);
+                return None;
+            }
+            _ => return None,
         };
-        match status {
+        if new == NatStatus::Private {
+            self.external_addrs.on_private();
+        }
+        match (&new, self.relay_manager.as_mut()) {
+            (NatStatus::Public(_), Some(manager)) => manager.on_public(),
+            (NatStatus::Private, Some(manager)) => manager.on_private(),
+            _ => {}
+        }
+        match &new {
             NatStatus::Public(addr) => log::info!("Public address confirmed: {addr}"),
             NatStatus::Private => log::warn!("Public address check failed."),
             NatStatus::Unknown => {}
         }
+        Some(ToSwarm::GenerateEvent(
+            BaseBehaviourEvent::NatStatusChanged { old, new },
+        ))
+    }
+
+    fn on_relay_server_event(&mut self, ev: relay::Event) -> Option<TToSwarm<Self>> {
+        match ev {
+            relay::Event::ReservationReqAccepted { src_peer_id, .. } => {
+                log::debug!("Relay reservation accepted for {src_peer_id}")
+            }
+            relay::Event::ReservationReqDenied { src_peer_id } => {
+                log::debug!("Relay reservation denied for {src_peer_id}")
+            }
+            relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => log::debug!("Relaying connection from {src_peer_id} to {dst_peer_id}"),
+            ev => log::trace!("Relay server event received: {ev:?}"),
+        }
         None
     }
 
@@ -561,7 +1858,539 @@ impl BaseBehaviour {
 
     fn on_nodes_update(&mut self, nodes: AuthorityPeers) -> Option<TToSwarm<Self>> {
         log::debug!("Updating registered workers");
+        // Local peers might have been discovered before they were known to be authorities
+        let newly_registered: Vec<_> = {
+            let registered = self.registered_nodes.read();
+            self.mdns_peers
+                .keys()
+                .filter(|peer_id| nodes.contains(peer_id) && !registered.contains(peer_id))
+                .copied()
+                .collect()
+        };
         *self.registered_nodes.write() = nodes;
+        for peer_id in newly_registered {
+            self.dial_mdns_peer(peer_id);
+        }
+        None
+    }
+
+    fn on_relay_client_event(&mut self, ev: relay::client::Event) -> Option<TToSwarm<Self>> {
+        log::debug!("Relay client event received: {ev:?}");
+        if let (
+            relay::client::Event::ReservationReqAccepted {
+                relay_peer_id,
+                renewal,
+                ..
+            },
+            Some(manager),
+        ) = (ev, self.relay_manager.as_mut())
+        {
+            manager.on_reservation_accepted(relay_peer_id, renewal);
+        }
+        None
+    }
+
+    fn on_dcutr_event(&mut self, ev: dcutr::Event) -> Option<TToSwarm<Self>> {
+        let peer_id = ev.remote_peer_id;
+        let result = match ev.result {
+            Ok(conn_id) => {
+                log::info!("Hole punching to {peer_id} succeeded");
+                self.hole_punch_stats.successes += 1;
+                Ok(conn_id)
+            }
+            Err(e) => {
+                log::info!("Hole punching to {peer_id} failed: {e}");
+                self.hole_punch_stats.failures += 1;
+                Err(e.to_string().into_boxed_str())
+            }
+        };
+        Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::HolePunched {
+            peer_id,
+            result,
+        }))
+    }
+
+    fn on_ping_event(&mut self, ev: ping::Event) -> Option<TToSwarm<Self>> {
+        let peer_id = ev.peer;
+        let latency = match ev.result {
+            Ok(rtt) => self.latencies.on_success(peer_id, rtt),
+            Err(ping::Failure::Unsupported) => return None,
+            Err(e) => {
+                log::debug!("Ping to {peer_id} failed: {e}");
+                self.latencies.on_failure(peer_id)
+            }
+        }
+        .clone();
+        if self.ping_max_failures > 0 && latency.consecutive_failures >= self.ping_max_failures {
+            log::info!(
+                "Disconnecting {peer_id} after {} failed pings",
+                latency.consecutive_failures
+            );
+            self.pending_events.push_back(ToSwarm::CloseConnection {
+                peer_id,
+                connection: Default::default(),
+            });
+        }
+        Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::PeerLatency {
+            peer_id,
+            latency,
+        }))
+    }
+
+    fn on_conn_manager_event(&mut self, ev: ConnManagerEvent) -> Option<TToSwarm<Self>> {
+        match ev {
+            ConnManagerEvent::ConnectivityChanged { connected, total } => {
+                Some(ToSwarm::GenerateEvent(
+                    BaseBehaviourEvent::AuthorityConnectivity { connected, total },
+                ))
+            }
+            ConnManagerEvent::LookupNeeded(peer_id) => {
+                self.find_and_dial(peer_id);
+                None
+            }
+        }
+    }
+
+    fn on_mdns_event(&mut self, ev: mdns::Event) -> Option<TToSwarm<Self>> {
+        match ev {
+            mdns::Event::Discovered(peers) => {
+                for (peer_id, addr) in peers {
+                    log::debug!("Discovered local peer {peer_id} at {addr}");
+                    self.inner
+                        .address_cache
+                        .put(peer_id, [addr.clone()], AddrSource::Mdns);
+                    self.inner.kademlia.add_address(&peer_id, addr.clone());
+                    let addrs = self.mdns_peers.entry(peer_id).or_default();
+                    let new_peer = addrs.is_empty();
+                    addrs.insert(addr);
+                    if new_peer {
+                        self.dial_mdns_peer(peer_id);
+                    }
+                }
+            }
+            mdns::Event::Expired(peers) => {
+                for (peer_id, addr) in peers {
+                    log::debug!("Local peer {peer_id} at {addr} expired");
+                    self.inner
+                        .address_cache
+                        .remove(peer_id, &addr, AddrSource::Mdns);
+                    self.inner.kademlia.remove_address(&peer_id, &addr);
+                    // Forget the peer only once all of its addresses expired
+                    if let Some(addrs) = self.mdns_peers.get_mut(&peer_id) {
+                        addrs.remove(&addr);
+                        if addrs.is_empty() {
+                            self.mdns_peers.remove(&peer_id);
+                        }
+                    }
+                }
+            }
+        }
         None
     }
+
+    fn dial_mdns_peer(&mut self, peer_id: PeerId) {
+        if !self.registered_nodes.read().contains(&peer_id) || self.outbound_conn_exists(&peer_id) {
+            return;
+        }
+        self.pending_events.push_back(ToSwarm::Dial {
+            opts: DialOpts::peer_id(peer_id).build(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::core::Endpoint;
+
+    use super::*;
+
+    fn behaviour() -> BaseBehaviour {
+        let keypair = Keypair::generate_ed25519();
+        let (_, relay) = relay::client::new(keypair.public().to_peer_id());
+        BaseBehaviour::new(
+            &keypair,
+            ContractClient::default(),
+            BaseConfig::default(),
+            vec![],
+            relay,
+            StreamProtocol::new("/test/kad/1.0.0"),
+            AgentInfo {
+                name: "test",
+                version: "0.0.0",
+            },
+        )
+        .unwrap()
+    }
+
+    fn dials(base: &mut BaseBehaviour) -> Vec<PeerId> {
+        base.pending_events
+            .drain(..)
+            .filter_map(|ev| match ev {
+                ToSwarm::Dial { opts } => opts.get_peer_id(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_mdns_discovery() {
+        let mut base = behaviour();
+        let (authority, other) = (PeerId::random(), PeerId::random());
+        let (authority_addr, other_addr): (Multiaddr, Multiaddr) = (
+            "/ip4/192.168.0.2/udp/1/quic-v1".parse().unwrap(),
+            "/ip4/192.168.0.3/udp/1/quic-v1".parse().unwrap(),
+        );
+        base.on_nodes_update([authority].into());
+
+        // Local addresses are cached for all peers, but only authorities are dialed
+        base.on_mdns_event(mdns::Event::Discovered(vec![
+            (authority, authority_addr.clone()),
+            (other, other_addr.clone()),
+        ]));
+        let cache = &base.inner.address_cache;
+        for (peer_id, addr) in [(authority, &authority_addr), (other, &other_addr)] {
+            let info = cache.addr_info(&peer_id, addr).unwrap();
+            assert_eq!(info.source, AddrSource::Mdns);
+        }
+        assert_eq!(dials(&mut base), [authority]);
+
+        // Rediscovering a peer doesn't dial it again
+        base.on_mdns_event(mdns::Event::Discovered(vec![(
+            authority,
+            authority_addr.clone(),
+        )]));
+        assert!(dials(&mut base).is_empty());
+
+        // Only the newly registered peer is dialed
+        base.on_nodes_update([authority, other].into());
+        assert_eq!(dials(&mut base), [other]);
+
+        // A peer with another live address isn't rediscovered
+        let other_addr6: Multiaddr = "/ip6/fd00::3/udp/1/quic-v1".parse().unwrap();
+        base.on_mdns_event(mdns::Event::Discovered(vec![(other, other_addr6.clone())]));
+        base.on_mdns_event(mdns::Event::Expired(vec![(other, other_addr6.clone())]));
+        base.on_mdns_event(mdns::Event::Discovered(vec![(other, other_addr6.clone())]));
+        assert!(dials(&mut base).is_empty());
+        base.on_mdns_event(mdns::Event::Expired(vec![(other, other_addr6)]));
+
+        // Expired peers are forgotten
+        base.on_mdns_event(mdns::Event::Expired(vec![(other, other_addr.clone())]));
+        assert!(base
+            .inner
+            .address_cache
+            .addr_info(&other, &other_addr)
+            .is_none());
+        base.on_nodes_update([authority].into());
+        base.on_nodes_update([authority, other].into());
+        assert!(dials(&mut base).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_boot_node_refresh() {
+        let mut base = behaviour();
+        let (kept, removed, added) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let node = |peer_id, address: &str| BootNode {
+            peer_id,
+            address: address.parse().unwrap(),
+        };
+        let resolved = vec![
+            node(kept, "/ip4/1.2.3.4/udp/1/quic-v1"),
+            node(removed, "/ip4/1.2.3.5/udp/1/quic-v1"),
+        ];
+        for boot_node in &resolved {
+            base.inner.add_boot_node(boot_node);
+            base.boot_nodes
+                .insert((boot_node.peer_id, boot_node.address.clone()));
+        }
+        base.set_boot_node_resolver(
+            BootNodeResolver::new(vec![], None).unwrap(),
+            resolved.clone(),
+        );
+        let cached = |base: &BaseBehaviour, node: &BootNode| {
+            base.inner
+                .address_cache
+                .addr_info(&node.peer_id, &node.address)
+                .is_some()
+        };
+
+        // Nodes missing after a failed lookup are kept
+        base.on_boot_nodes_resolved(ResolvedBootNodes {
+            boot_nodes: vec![resolved[0].clone()],
+            complete: false,
+        });
+        assert!(cached(&base, &resolved[1]));
+        assert!(dials(&mut base).is_empty());
+
+        // New nodes are dialed, the ones no longer listed dropped
+        let new = node(added, "/ip4/1.2.3.6/udp/1/quic-v1");
+        base.on_boot_nodes_resolved(ResolvedBootNodes {
+            boot_nodes: vec![resolved[0].clone(), new.clone()],
+            complete: true,
+        });
+        assert_eq!(dials(&mut base), [added]);
+        assert!(cached(&base, &resolved[0]) && cached(&base, &new));
+        assert!(!cached(&base, &resolved[1]));
+        assert_eq!(base.boot_nodes.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_dht_address_policy() {
+        let mut base = behaviour();
+        let peer_id = PeerId::random();
+        let (local, private, public): (Multiaddr, Multiaddr, Multiaddr) = (
+            "/ip4/192.168.0.2/udp/1/quic-v1".parse().unwrap(),
+            "/ip4/10.0.0.2/udp/1/quic-v1".parse().unwrap(),
+            "/ip4/1.2.3.4/udp/1/quic-v1".parse().unwrap(),
+        );
+        let dial_addrs = |base: &mut BaseBehaviour| {
+            let addrs = base
+                .inner
+                .handle_pending_outbound_connection(
+                    ConnectionId::new_unchecked(0),
+                    Some(peer_id),
+                    &[],
+                    Endpoint::Dialer,
+                )
+                .unwrap();
+            let mut addrs: Vec<_> = base
+                .filter_dial_addresses(ConnectionId::new_unchecked(0), Some(peer_id), addrs)
+                .into_iter()
+                .map(|addr| addr.with_p2p(peer_id).unwrap())
+                .collect();
+            addrs.sort();
+            addrs.dedup();
+            addrs
+        };
+
+        base.inner.whitelist.allow_peer(peer_id);
+
+        // Addresses discovered locally are trusted, others learned by the DHT are checked
+        base.on_mdns_event(mdns::Event::Discovered(vec![(peer_id, local.clone())]));
+        base.inner.kademlia.add_address(&peer_id, private.clone());
+        base.inner.kademlia.add_address(&peer_id, public.clone());
+        let mut expected = vec![
+            local.clone().with_p2p(peer_id).unwrap(),
+            public.clone().with_p2p(peer_id).unwrap(),
+        ];
+        expected.sort();
+        assert_eq!(dial_addrs(&mut base), expected);
+
+        // Denied addresses are removed from the routing table
+        let mut addresses = kad::Addresses::new(local.clone());
+        addresses.insert(private.clone());
+        addresses.insert(public.clone());
+        base.on_kademlia_event(kad::Event::RoutingUpdated {
+            peer: peer_id,
+            is_new_peer: true,
+            addresses,
+            bucket_range: (
+                kad::KBucketDistance::default(),
+                kad::KBucketDistance::default(),
+            ),
+            old_peer: None,
+        });
+        let routed = base
+            .inner
+            .kademlia
+            .kbucket(peer_id)
+            .and_then(|bucket| {
+                bucket
+                    .iter()
+                    .find(|entry| entry.node.key.preimage() == &peer_id)
+                    .map(|entry| entry.node.value.clone().into_vec())
+            })
+            .unwrap();
+        assert!(!routed.contains(&private.with_p2p(peer_id).unwrap()));
+        assert!(routed.contains(&public.with_p2p(peer_id).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_running_probe() {
+        let mut base = behaviour();
+        let cancelled = |base: &mut BaseBehaviour, peer: PeerId| {
+            base.pending_events.iter().any(|ev| {
+                matches!(ev, ToSwarm::GenerateEvent(BaseBehaviourEvent::PeerProbed(PeerProbed {
+                    peer_id,
+                    result: ProbeResult::Error { kind: ProbeErrorKind::Cancelled, .. },
+                })) if *peer_id == peer)
+            })
+        };
+        let closed = |base: &mut BaseBehaviour, conn_id: ConnectionId| {
+            base.pending_events.drain(..).any(|ev| {
+                matches!(ev, ToSwarm::CloseConnection {
+                    connection: CloseConnection::One(id),
+                    ..
+                } if id == conn_id)
+            })
+        };
+
+        // The DHT lookup is stopped
+        let peer_id = PeerId::random();
+        base.try_probe_dht(peer_id).unwrap();
+        let query_id = *base.ongoing_queries.get_by_left(&peer_id).unwrap();
+        assert!(base.inner.kademlia.query(&query_id).is_some());
+        assert!(base.cancel_probe(peer_id));
+        assert!(cancelled(&mut base, peer_id));
+        assert!(base.ongoing_queries.is_empty());
+        assert!(base.inner.kademlia.query(&query_id).is_none());
+        base.pending_events.clear();
+
+        // The dial is closed once it connects
+        let peer_id = PeerId::random();
+        let addr: Multiaddr = "/ip4/1.2.3.4/udp/1/quic-v1".parse().unwrap();
+        base.try_probe_direct(peer_id, [addr.clone()]).unwrap();
+        let conn_id = *base.pending_outbound_conns.get_by_left(&peer_id).unwrap();
+        assert!(base.cancel_probe(peer_id));
+        assert!(cancelled(&mut base, peer_id));
+        assert!(!closed(&mut base, conn_id));
+        let endpoint = ConnectedPoint::Dialer {
+            address: addr,
+            role_override: Endpoint::Dialer,
+            port_use: Default::default(),
+        };
+        base.on_connection_established(ConnectionEstablished {
+            peer_id,
+            connection_id: conn_id,
+            endpoint: &endpoint,
+            failed_addresses: &[],
+            other_established: 0,
+        });
+        assert!(closed(&mut base, conn_id));
+        assert!(!base.probe_timeouts.contains(peer_id));
+    }
+
+    #[tokio::test]
+    async fn test_probe_slots() {
+        let mut base = behaviour();
+        base.max_concurrent_probes = 1;
+        base.probe_queue = ProbeQueue::new(0);
+
+        // A free slot is used without queueing
+        let peer_id = PeerId::random();
+        base.try_probe_dht(peer_id).unwrap();
+        let query_id = *base.ongoing_queries.get_by_left(&peer_id).unwrap();
+        assert!(matches!(
+            base.try_probe_dht(PeerId::random()),
+            Err(TryProbeError::TooManyProbes)
+        ));
+
+        // A timed out probe is stopped like a cancelled one
+        _ = base.probe_timeouts.remove(peer_id);
+        let ev = base.on_probe_timeout(peer_id);
+        assert!(matches!(
+            ev,
+            ToSwarm::GenerateEvent(BaseBehaviourEvent::PeerProbed(PeerProbed {
+                result: ProbeResult::Error {
+                    kind: ProbeErrorKind::Timeout,
+                    ..
+                },
+                ..
+            }))
+        ));
+        assert!(base.ongoing_queries.is_empty());
+        assert!(base.inner.kademlia.query(&query_id).is_none());
+
+        // The slot is freed once the timeouts are polled again
+        std::future::poll_fn(|cx| {
+            assert!(base.probe_timeouts.poll_unpin(cx).is_pending());
+            Poll::Ready(())
+        })
+        .await;
+        let peer_id = PeerId::random();
+        let addr: Multiaddr = "/ip4/1.2.3.4/udp/1/quic-v1".parse().unwrap();
+        base.try_probe_direct(peer_id, [addr]).unwrap();
+        let conn_id = *base.pending_outbound_conns.get_by_left(&peer_id).unwrap();
+        _ = base.probe_timeouts.remove(peer_id);
+        base.on_probe_timeout(peer_id);
+        assert!(base.cancelled_dials.contains(&conn_id));
+    }
+
+    #[tokio::test]
+    async fn test_get_record_quorum() {
+        let mut base = behaviour();
+        let publisher = Keypair::generate_ed25519();
+        base.registered_nodes
+            .write()
+            .insert(publisher.public().to_peer_id());
+        let key = RecordKey::new(b"checkpoint");
+        let found = |seq: u64| {
+            let record = sign_record(&publisher, key.clone(), seq, seq.encode());
+            Ok(GetRecordOk::FoundRecord(kad::PeerRecord {
+                peer: None,
+                record,
+            }))
+        };
+        let quorum = Quorum::N(2.try_into().unwrap());
+
+        // The newest record is reported, even if an older one was found first
+        let query_id = base.get_record(b"checkpoint", quorum);
+        assert!(base
+            .on_get_record_progress(query_id, found(2), false)
+            .is_none());
+        let Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::RecordFound { result, .. })) =
+            base.on_get_record_progress(query_id, found(3), false)
+        else {
+            panic!("Record should be reported once the quorum is met");
+        };
+        assert_eq!(result.unwrap().seq, 3);
+
+        let query_id = base.get_record(b"checkpoint", quorum);
+        base.on_get_record_progress(query_id, found(3), false);
+        let Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::RecordFound { result, .. })) =
+            base.on_get_record_progress(query_id, found(1), false)
+        else {
+            panic!("Record should be reported once the quorum is met");
+        };
+        assert_eq!(result.unwrap().seq, 3);
+
+        // A single record doesn't meet the quorum
+        let query_id = base.get_record(b"checkpoint", quorum);
+        base.on_get_record_progress(query_id, found(1), false);
+        let last = Ok(GetRecordOk::FinishedWithNoAdditionalRecord {
+            cache_candidates: Default::default(),
+        });
+        let Some(ToSwarm::GenerateEvent(BaseBehaviourEvent::RecordFound { result, .. })) =
+            base.on_get_record_progress(query_id, last, true)
+        else {
+            panic!("Result should be reported when the lookup ends");
+        };
+        assert!(matches!(
+            result,
+            Err(RecordError::QuorumFailed {
+                success: 1,
+                quorum: 2
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_provider_addresses() {
+        let mut base = behaviour();
+        let provider = PeerId::random();
+        let addr: Multiaddr = "/ip4/1.2.3.4/udp/1/quic-v1".parse().unwrap();
+        let query_id = base.get_providers(b"block-42");
+        let found = Ok(GetProvidersOk::FoundProviders {
+            key: RecordKey::new(b"block-42"),
+            providers: [provider].into(),
+        });
+        assert!(base
+            .on_get_providers_progress(query_id, found, false)
+            .is_none());
+
+        // Found providers are dialed, and the addresses offered for the dial are cached
+        let dial = base.pending_events.drain(..).find_map(|ev| match ev {
+            ToSwarm::Dial { opts } if opts.get_peer_id() == Some(provider) => {
+                Some(opts.connection_id())
+            }
+            _ => None,
+        });
+        let dial = dial.expect("provider should be dialed");
+        base.filter_dial_addresses(dial, Some(provider), vec![addr.clone()]);
+        let info = base
+            .inner
+            .address_cache
+            .addr_info(&provider, &addr)
+            .unwrap();
+        assert_eq!(info.source, AddrSource::Dht);
+    }
 }
@@ -0,0 +1,460 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use libp2p::{
+    core::{transport::PortUse, upgrade::DeniedUpgrade, Endpoint},
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        handler::ConnectionEvent,
+        ConnectionClosed, ConnectionDenied, ConnectionHandlerEvent, ConnectionId, DialError,
+        DialFailure, FromSwarm, NetworkBehaviour, NotifyHandler, SubstreamProtocol, THandler,
+        THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use parking_lot::RwLock;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use crate::utils::poll_ticks;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ConnManagerConfig {
+    /// Delay before the first reconnection attempt, doubled after every failed one.
+    pub backoff_min: Duration,
+    /// Maximum delay between reconnection attempts.
+    pub backoff_max: Duration,
+    /// How often to check for authorities to dial.
+    pub check_interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub enum ConnManagerEvent {
+    /// The number of connected authorities or the size of the authority set changed.
+    ConnectivityChanged { connected: usize, total: usize },
+    /// Dialing the authority failed, because no address is known or none of the known ones
+    /// worked. Its current addresses need to be looked up in the DHT.
+    LookupNeeded(PeerId),
+}
+
+#[derive(Default)]
+struct AuthorityState {
+    /// Failed (or still pending) dial attempts since the last connection
+    attempts: u32,
+    next_dial: Option<Instant>,
+}
+
+/// Keeps connections to all registered authorities open, redialing them with exponential
+/// backoff when they get dropped.
+pub struct ConnectionManager {
+    local_peer_id: PeerId,
+    config: ConnManagerConfig,
+    registered_nodes: Arc<RwLock<HashSet<PeerId>>>,
+    authorities: HashMap<PeerId, AuthorityState>,
+    connections: HashMap<PeerId, HashSet<ConnectionId>>,
+    /// Dials started by the manager, so that only their failures trigger lookups
+    pending_dials: HashSet<ConnectionId>,
+    check_interval: Interval,
+    last_connectivity: (usize, usize),
+    pending_events: VecDeque<ToSwarm<ConnManagerEvent, bool>>,
+}
+
+impl ConnectionManager {
+    pub fn new(
+        local_peer_id: PeerId,
+        config: ConnManagerConfig,
+        registered_nodes: Arc<RwLock<HashSet<PeerId>>>,
+    ) -> Self {
+        let mut check_interval = interval(config.check_interval);
+        check_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            local_peer_id,
+            config,
+            registered_nodes,
+            authorities: Default::default(),
+            connections: Default::default(),
+            pending_dials: Default::default(),
+            check_interval,
+            last_connectivity: (0, 0),
+            pending_events: Default::default(),
+        }
+    }
+
+    /// Number of connected authorities and the size of the authority set.
+    pub fn connectivity(&self) -> (usize, usize) {
+        let connected = self
+            .authorities
+            .keys()
+            .filter(|peer_id| self.is_connected(peer_id))
+            .count();
+        (connected, self.authorities.len())
+    }
+
+    fn is_connected(&self, peer_id: &PeerId) -> bool {
+        self.connections.get(peer_id).is_some_and(|c| !c.is_empty())
+    }
+
+    fn backoff(&self, attempts: u32) -> Duration {
+        let exp = attempts.saturating_sub(1).min(16);
+        let delay = self
+            .config
+            .backoff_min
+            .saturating_mul(1 << exp)
+            .min(self.config.backoff_max);
+        // Equal jitter: somewhere between half and the full delay
+        delay / 2 + delay.mul_f64(rand::thread_rng().gen::<f64>() / 2.0)
+    }
+
+    fn set_keep_alive(&mut self, peer_id: PeerId, keep_alive: bool) {
+        for conn_id in self.connections.get(&peer_id).into_iter().flatten() {
+            self.pending_events.push_back(ToSwarm::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::One(*conn_id),
+                event: keep_alive,
+            });
+        }
+    }
+
+    fn sync_authorities(&mut self, now: Instant) {
+        let registered = self.registered_nodes.read().clone();
+        let removed: Vec<_> = self
+            .authorities
+            .keys()
+            .filter(|peer_id| !registered.contains(peer_id))
+            .copied()
+            .collect();
+        for peer_id in removed {
+            log::debug!("Authority {peer_id} removed, no longer maintaining connection");
+            self.authorities.remove(&peer_id);
+            self.set_keep_alive(peer_id, false);
+        }
+        for peer_id in registered {
+            if peer_id == self.local_peer_id || self.authorities.contains_key(&peer_id) {
+                continue;
+            }
+            self.authorities.insert(
+                peer_id,
+                AuthorityState {
+                    attempts: 0,
+                    next_dial: Some(now),
+                },
+            );
+            self.set_keep_alive(peer_id, true);
+        }
+    }
+
+    fn dial_due(&mut self, now: Instant) {
+        let due: Vec<_> = self
+            .authorities
+            .iter()
+            .filter(|(peer_id, state)| {
+                !self.is_connected(peer_id) && state.next_dial.is_some_and(|t| t <= now)
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in due {
+            let attempts = self.authorities[&peer_id].attempts + 1;
+            let next_dial = now + self.backoff(attempts);
+            let state = self
+                .authorities
+                .get_mut(&peer_id)
+                .expect("authority exists");
+            state.attempts = attempts;
+            state.next_dial = Some(next_dial);
+            log::debug!("Dialing authority {peer_id} (attempt {attempts})");
+            let opts = DialOpts::peer_id(peer_id)
+                .condition(PeerCondition::DisconnectedAndNotDialing)
+                .build();
+            self.pending_dials.insert(opts.connection_id());
+            self.pending_events.push_back(ToSwarm::Dial { opts });
+        }
+    }
+
+    fn report_connectivity(&mut self) {
+        let connectivity = self.connectivity();
+        if connectivity == self.last_connectivity {
+            return;
+        }
+        self.last_connectivity = connectivity;
+        let (connected, total) = connectivity;
+        log::info!("Connected to {connected}/{total} authorities");
+        self.pending_events.push_back(ToSwarm::GenerateEvent(
+            ConnManagerEvent::ConnectivityChanged { connected, total },
+        ));
+    }
+
+    fn on_connection_established(&mut self, peer_id: PeerId, conn_id: ConnectionId) {
+        self.connections.entry(peer_id).or_default().insert(conn_id);
+        if let Some(state) = self.authorities.get_mut(&peer_id) {
+            state.attempts = 0;
+            state.next_dial = None;
+        }
+        self.report_connectivity();
+    }
+
+    fn on_connection_closed(&mut self, peer_id: PeerId, conn_id: ConnectionId) {
+        if let Some(conns) = self.connections.get_mut(&peer_id) {
+            conns.remove(&conn_id);
+            if conns.is_empty() {
+                self.connections.remove(&peer_id);
+            }
+        }
+        if self.is_connected(&peer_id) {
+            return;
+        }
+        if let Some(state) = self.authorities.get_mut(&peer_id) {
+            log::debug!("Lost connection to authority {peer_id}");
+            state.next_dial = Some(Instant::now());
+        }
+        self.report_connectivity();
+    }
+
+    fn new_handler(&self, peer_id: &PeerId) -> KeepAliveHandler {
+        KeepAliveHandler {
+            keep_alive: self.authorities.contains_key(peer_id),
+        }
+    }
+}
+
+impl NetworkBehaviour for ConnectionManager {
+    type ConnectionHandler = KeepAliveHandler;
+    type ToSwarm = ConnManagerEvent;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        peer: PeerId,
+        _local_addr: &Multiaddr,
+        _remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(self.new_handler(&peer))
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        peer: PeerId,
+        _addr: &Multiaddr,
+        _role_override: Endpoint,
+        _port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(self.new_handler(&peer))
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(e) => {
+                self.pending_dials.remove(&e.connection_id);
+                self.on_connection_established(e.peer_id, e.connection_id)
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                connection_id,
+                ..
+            }) => self.on_connection_closed(peer_id, connection_id),
+            FromSwarm::DialFailure(DialFailure {
+                peer_id: Some(peer_id),
+                error,
+                connection_id,
+            }) => {
+                // Every own dial is a backoff step, so this looks up at most once per step
+                let own_dial = self.pending_dials.remove(&connection_id);
+                let stale_addrs = matches!(
+                    error,
+                    DialError::NoAddresses
+                        | DialError::Transport(_)
+                        | DialError::WrongPeerId { .. }
+                );
+                if own_dial && stale_addrs && self.authorities.contains_key(&peer_id) {
+                    self.pending_events.push_back(ToSwarm::GenerateEvent(
+                        ConnManagerEvent::LookupNeeded(peer_id),
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        if let Some(ev) = self.pending_events.pop_front() {
+            return Poll::Ready(ev);
+        }
+        if poll_ticks(&mut self.check_interval, cx) {
+            let now = Instant::now();
+            self.sync_authorities(now);
+            self.dial_due(now);
+            self.report_connectivity();
+            if let Some(ev) = self.pending_events.pop_front() {
+                return Poll::Ready(ev);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Connection handler which doesn't support any protocol, only keeps authority connections open.
+pub struct KeepAliveHandler {
+    keep_alive: bool,
+}
+
+impl libp2p::swarm::ConnectionHandler for KeepAliveHandler {
+    type FromBehaviour = bool;
+    type ToBehaviour = Infallible;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::ToBehaviour>,
+    > {
+        Poll::Pending
+    }
+
+    fn on_behaviour_event(&mut self, keep_alive: bool) {
+        self.keep_alive = keep_alive;
+    }
+
+    fn on_connection_event(
+        &mut self,
+        _event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(authorities: &[PeerId]) -> ConnectionManager {
+        let config = ConnManagerConfig {
+            backoff_min: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(60),
+            check_interval: Duration::from_secs(1),
+        };
+        let registered = Arc::new(RwLock::new(authorities.iter().copied().collect()));
+        ConnectionManager::new(PeerId::random(), config, registered)
+    }
+
+    fn dials(manager: &mut ConnectionManager) -> Vec<PeerId> {
+        manager
+            .pending_events
+            .drain(..)
+            .filter_map(|ev| match ev {
+                ToSwarm::Dial { opts } => opts.get_peer_id(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_backoff() {
+        let peer_id = PeerId::random();
+        let mut manager = manager(&[peer_id]);
+        let start = Instant::now();
+
+        manager.sync_authorities(start);
+        manager.dial_due(start);
+        assert_eq!(dials(&mut manager), [peer_id]);
+        // The next attempt is delayed by 0.5-1s, then 1-2s, then 2-4s
+        manager.dial_due(start + Duration::from_millis(400));
+        assert!(dials(&mut manager).is_empty());
+        manager.dial_due(start + Duration::from_secs(1));
+        assert_eq!(dials(&mut manager), [peer_id]);
+        manager.dial_due(start + Duration::from_millis(1900));
+        assert!(dials(&mut manager).is_empty());
+        manager.dial_due(start + Duration::from_secs(3));
+        assert_eq!(dials(&mut manager), [peer_id]);
+
+        // Connected authorities are not dialed, but redialed right after disconnecting
+        let conn_id = ConnectionId::new_unchecked(1);
+        manager.on_connection_established(peer_id, conn_id);
+        assert_eq!(manager.connectivity(), (1, 1));
+        manager.dial_due(start + Duration::from_secs(60));
+        assert!(dials(&mut manager).is_empty());
+        manager.on_connection_closed(peer_id, conn_id);
+        assert_eq!(manager.connectivity(), (0, 1));
+        manager.dial_due(Instant::now());
+        assert_eq!(dials(&mut manager), [peer_id]);
+    }
+
+    #[tokio::test]
+    async fn test_removed_authority() {
+        let peer_id = PeerId::random();
+        let mut manager = manager(&[peer_id]);
+        let now = Instant::now();
+        manager.sync_authorities(now);
+        manager.registered_nodes.write().clear();
+        manager.sync_authorities(now);
+        manager.dial_due(now);
+        assert!(dials(&mut manager).is_empty());
+        assert_eq!(manager.connectivity(), (0, 0));
+    }
+
+    /// Report a failed dial, returning whether a lookup was requested.
+    fn fail_dial(manager: &mut ConnectionManager, peer_id: PeerId, conn_id: ConnectionId) -> bool {
+        manager.on_swarm_event(FromSwarm::DialFailure(DialFailure {
+            peer_id: Some(peer_id),
+            error: &DialError::Transport(vec![]),
+            connection_id: conn_id,
+        }));
+        manager.pending_events.drain(..).any(|ev| {
+            matches!(ev, ToSwarm::GenerateEvent(ConnManagerEvent::LookupNeeded(p)) if p == peer_id)
+        })
+    }
+
+    #[tokio::test]
+    async fn test_lookup_after_failed_dial() {
+        let peer_id = PeerId::random();
+        let mut manager = manager(&[peer_id]);
+        let now = Instant::now();
+        manager.sync_authorities(now);
+        manager.pending_events.clear();
+        manager.dial_due(now);
+        let conn_id = match manager.pending_events.pop_front() {
+            Some(ToSwarm::Dial { opts }) => opts.connection_id(),
+            _ => panic!("Authority not dialed"),
+        };
+
+        // Failures of other dials don't trigger lookups
+        let other = ConnectionId::new_unchecked(1);
+        assert!(!fail_dial(&mut manager, peer_id, other));
+        assert!(fail_dial(&mut manager, peer_id, conn_id));
+        // Only once per attempt
+        assert!(!fail_dial(&mut manager, peer_id, conn_id));
+    }
+}
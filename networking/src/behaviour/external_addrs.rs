@@ -0,0 +1,265 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use libp2p::{
+    swarm::{ExternalAddresses, FromSwarm, NewExternalAddrCandidate, ToSwarm},
+    Multiaddr,
+};
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
+
+use crate::utils::poll_ticks;
+
+/// Maximum number of candidate addresses kept. The least recently observed one is dropped.
+const MAX_CANDIDATES: usize = 32;
+/// Maximum time between checks for expired addresses.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Address under which peers observed the local node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrCandidate {
+    pub addr: Multiaddr,
+    /// Number of times the address was reported by identify
+    pub observations: u32,
+    /// Confirmed by an AutoNAT dial-back and currently used as external address
+    pub confirmed: bool,
+}
+
+struct Candidate {
+    observations: u32,
+    last_seen: Instant,
+}
+
+/// Expires external addresses confirmed by AutoNAT once they're no longer confirmed.
+///
+/// AutoNAT itself adds the addresses its dial-backs reach as external addresses. Its probe
+/// result is reported before the address is confirmed, which tells the addresses confirmed
+/// by AutoNAT apart from those set by other means, like `--p2p-public-addrs` or relay
+/// circuits. The latter are left alone.
+pub struct ExternalAddrManager {
+    ttl: Duration,
+    candidates: HashMap<Multiaddr, Candidate>,
+    /// Reached by an AutoNAT dial-back, about to be confirmed by AutoNAT
+    reached: HashSet<Multiaddr>,
+    /// Addresses confirmed by AutoNAT, with the time of their latest confirmation
+    confirmed: HashMap<Multiaddr, Instant>,
+    external_addrs: ExternalAddresses,
+    expiry_check: Interval,
+    pending_events: VecDeque<ToSwarm<Infallible, Infallible>>,
+}
+
+impl ExternalAddrManager {
+    /// Confirmed addresses expire if they aren't confirmed again within `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        let period = ttl.clamp(Duration::from_secs(1), EXPIRY_CHECK_INTERVAL);
+        let mut expiry_check = interval_at(Instant::now() + period, period);
+        expiry_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            ttl,
+            candidates: Default::default(),
+            reached: Default::default(),
+            confirmed: Default::default(),
+            external_addrs: Default::default(),
+            expiry_check,
+            pending_events: Default::default(),
+        }
+    }
+
+    pub fn candidates(&self) -> Vec<AddrCandidate> {
+        self.candidates
+            .iter()
+            .map(|(addr, candidate)| AddrCandidate {
+                addr: addr.clone(),
+                observations: candidate.observations,
+                confirmed: self.confirmed.contains_key(addr),
+            })
+            .collect()
+    }
+
+    /// Returns whether the external addresses of the node changed.
+    pub fn on_swarm_event(&mut self, ev: &FromSwarm) -> bool {
+        match ev {
+            FromSwarm::NewExternalAddrCandidate(NewExternalAddrCandidate { addr }) => {
+                self.on_candidate((*addr).clone())
+            }
+            FromSwarm::ExternalAddrConfirmed(e) if self.reached.remove(e.addr) => {
+                log::info!("External address confirmed: {}", e.addr);
+                self.confirmed.insert(e.addr.clone(), Instant::now());
+            }
+            FromSwarm::ExternalAddrExpired(e) => {
+                self.confirmed.remove(e.addr);
+            }
+            _ => {}
+        }
+        self.external_addrs.on_swarm_event(ev)
+    }
+
+    /// AutoNAT reached the node on `addr`. AutoNAT confirms the address right after.
+    pub fn on_confirmed(&mut self, addr: Multiaddr) {
+        if let Some(confirmed_at) = self.confirmed.get_mut(&addr) {
+            *confirmed_at = Instant::now();
+            return;
+        }
+        if self.external_addrs.iter().any(|a| *a == addr) {
+            log::debug!("External address {addr} not managed, ignoring confirmation");
+            return;
+        }
+        self.reached.insert(addr);
+    }
+
+    /// AutoNAT found the node private, so none of the confirmed addresses work anymore.
+    pub fn on_private(&mut self) {
+        for addr in self.confirmed.keys().cloned().collect::<Vec<_>>() {
+            self.expire(addr);
+        }
+    }
+
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<Infallible, Infallible>> {
+        if poll_ticks(&mut self.expiry_check, cx) {
+            self.expire_stale(Instant::now());
+        }
+        match self.pending_events.pop_front() {
+            Some(ev) => Poll::Ready(ev),
+            None => Poll::Pending,
+        }
+    }
+
+    fn on_candidate(&mut self, addr: Multiaddr) {
+        let now = Instant::now();
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.observations += 1;
+            candidate.last_seen = now;
+            return;
+        }
+        log::debug!("New external address candidate: {addr}");
+        if self.candidates.len() == MAX_CANDIDATES {
+            let oldest = self
+                .candidates
+                .iter()
+                .filter(|(addr, _)| !self.confirmed.contains_key(*addr))
+                .min_by_key(|(_, candidate)| candidate.last_seen)
+                .map(|(addr, _)| addr.clone());
+            match oldest {
+                Some(oldest) => self.candidates.remove(&oldest),
+                None => return,
+            };
+        }
+        self.candidates.insert(
+            addr,
+            Candidate {
+                observations: 1,
+                last_seen: now,
+            },
+        );
+    }
+
+    fn expire_stale(&mut self, now: Instant) {
+        let stale: Vec<_> = self
+            .confirmed
+            .iter()
+            .filter(|(_, confirmed_at)| now.duration_since(**confirmed_at) >= self.ttl)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in stale {
+            self.expire(addr);
+        }
+    }
+
+    fn expire(&mut self, addr: Multiaddr) {
+        log::info!("External address no longer confirmed: {addr}");
+        self.confirmed.remove(&addr);
+        self.pending_events
+            .push_back(ToSwarm::ExternalAddrExpired(addr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::task::noop_waker_ref;
+    use libp2p::swarm::behaviour::{ExternalAddrConfirmed, ExternalAddrExpired};
+
+    use super::*;
+
+    fn poll_events(manager: &mut ExternalAddrManager) -> Vec<ToSwarm<Infallible, Infallible>> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        std::iter::from_fn(|| match manager.poll(&mut cx) {
+            Poll::Ready(ev) => Some(ev),
+            Poll::Pending => None,
+        })
+        .collect()
+    }
+
+    fn confirm(manager: &mut ExternalAddrManager, addr: &Multiaddr) -> bool {
+        manager.on_swarm_event(&FromSwarm::ExternalAddrConfirmed(ExternalAddrConfirmed {
+            addr,
+        }))
+    }
+
+    /// Feed the events back like the swarm does, returning whether external addresses changed.
+    fn apply(
+        manager: &mut ExternalAddrManager,
+        events: &[ToSwarm<Infallible, Infallible>],
+    ) -> bool {
+        let mut changed = false;
+        for ev in events {
+            if let ToSwarm::ExternalAddrExpired(addr) = ev {
+                changed |=
+                    manager.on_swarm_event(&FromSwarm::ExternalAddrExpired(ExternalAddrExpired {
+                        addr,
+                    }));
+            }
+        }
+        changed
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_confirm_and_expire() {
+        let mut manager = ExternalAddrManager::new(Duration::from_secs(600));
+        let observed: Multiaddr = "/ip4/1.2.3.4/udp/1/quic-v1".parse().unwrap();
+        let static_addr: Multiaddr = "/ip4/5.6.7.8/udp/1/quic-v1".parse().unwrap();
+        for _ in 0..2 {
+            manager.on_swarm_event(&FromSwarm::NewExternalAddrCandidate(
+                NewExternalAddrCandidate { addr: &observed },
+            ));
+        }
+        assert!(confirm(&mut manager, &static_addr));
+        assert_eq!(
+            manager.candidates(),
+            [AddrCandidate {
+                addr: observed.clone(),
+                observations: 2,
+                confirmed: false,
+            }]
+        );
+
+        // AutoNAT confirms the addresses it reaches itself, only the observed one is managed
+        manager.on_confirmed(observed.clone());
+        manager.on_confirmed(static_addr.clone());
+        assert!(confirm(&mut manager, &observed));
+        assert!(!confirm(&mut manager, &static_addr));
+        assert!(poll_events(&mut manager).is_empty());
+        assert!(manager.candidates()[0].confirmed);
+
+        // Confirming again keeps the address, without changing external addresses
+        tokio::time::advance(Duration::from_secs(400)).await;
+        manager.on_confirmed(observed.clone());
+        tokio::time::advance(Duration::from_secs(400)).await;
+        assert!(poll_events(&mut manager).is_empty());
+
+        tokio::time::advance(Duration::from_secs(300)).await;
+        let events = poll_events(&mut manager);
+        assert!(matches!(&events[..], [ToSwarm::ExternalAddrExpired(a)] if *a == observed));
+        assert!(apply(&mut manager, &events));
+        assert!(!manager.candidates()[0].confirmed);
+
+        // Turning private expires the confirmed addresses right away
+        manager.on_confirmed(observed.clone());
+        confirm(&mut manager, &observed);
+        manager.on_private();
+        let events = poll_events(&mut manager);
+        assert!(matches!(&events[..], [ToSwarm::ExternalAddrExpired(a)] if *a == observed));
+    }
+}
@@ -0,0 +1,108 @@
+use std::{collections::HashMap, time::Duration};
+
+use libp2p::PeerId;
+
+/// Weight of the newest sample in the moving average.
+const EWMA_ALPHA: f64 = 0.2;
+
+/// Round-trip times measured by ping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttStats {
+    pub last: Duration,
+    /// Exponentially weighted moving average
+    pub ewma: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    pub samples: u64,
+}
+
+impl RttStats {
+    fn new(rtt: Duration) -> Self {
+        Self {
+            last: rtt,
+            ewma: rtt,
+            min: rtt,
+            max: rtt,
+            samples: 1,
+        }
+    }
+
+    fn add(&mut self, rtt: Duration) {
+        self.last = rtt;
+        self.ewma = self.ewma.mul_f64(1.0 - EWMA_ALPHA) + rtt.mul_f64(EWMA_ALPHA);
+        self.min = self.min.min(rtt);
+        self.max = self.max.max(rtt);
+        self.samples += 1;
+    }
+}
+
+/// Health of the connection(s) to a peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerLatency {
+    /// Not set until the first successful ping
+    pub rtt: Option<RttStats>,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+}
+
+/// Ping results of all connected peers.
+#[derive(Default)]
+pub struct LatencyTracker {
+    peers: HashMap<PeerId, PeerLatency>,
+}
+
+impl LatencyTracker {
+    pub fn get(&self, peer_id: &PeerId) -> Option<&PeerLatency> {
+        self.peers.get(peer_id)
+    }
+
+    pub fn on_success(&mut self, peer_id: PeerId, rtt: Duration) -> &PeerLatency {
+        let latency = self.peers.entry(peer_id).or_default();
+        match &mut latency.rtt {
+            Some(stats) => stats.add(rtt),
+            None => latency.rtt = Some(RttStats::new(rtt)),
+        }
+        latency.consecutive_failures = 0;
+        latency
+    }
+
+    pub fn on_failure(&mut self, peer_id: PeerId) -> &PeerLatency {
+        let latency = self.peers.entry(peer_id).or_default();
+        latency.failures += 1;
+        latency.consecutive_failures += 1;
+        latency
+    }
+
+    /// Forget the peer after its last connection closed.
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_stats() {
+        let peer_id = PeerId::random();
+        let mut tracker = LatencyTracker::default();
+        assert_eq!(tracker.on_failure(peer_id).rtt, None);
+
+        tracker.on_success(peer_id, Duration::from_millis(100));
+        let latency = tracker.on_success(peer_id, Duration::from_millis(200));
+        assert_eq!(latency.consecutive_failures, 0);
+        assert_eq!(latency.failures, 1);
+        let rtt = latency.rtt.unwrap();
+        assert_eq!(rtt.last, Duration::from_millis(200));
+        assert_eq!(rtt.ewma, Duration::from_millis(120));
+        assert_eq!(rtt.min, Duration::from_millis(100));
+        assert_eq!(rtt.max, Duration::from_millis(200));
+        assert_eq!(rtt.samples, 2);
+
+        tracker.on_failure(peer_id);
+        assert_eq!(tracker.on_failure(peer_id).consecutive_failures, 2);
+        tracker.remove(&peer_id);
+        assert!(tracker.get(&peer_id).is_none());
+    }
+}
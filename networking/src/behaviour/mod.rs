@@ -1,5 +1,15 @@
 pub mod addr_cache;
+pub mod address_policy;
 pub mod base;
+pub mod conn_manager;
+pub mod external_addrs;
+pub mod latency;
+pub mod probe_queue;
 pub mod pubsub;
+pub mod reachability;
+pub mod record;
+pub mod record_store;
+pub mod relay_manager;
+pub mod relay_server;
 pub mod whitelist;
 pub mod wrapped;
@@ -0,0 +1,187 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::FutureExt;
+use libp2p::{Multiaddr, PeerId};
+use tokio::sync::oneshot;
+
+use super::base::{ProbeErrorKind, ProbeResult, TryProbeError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProbePriority {
+    Normal,
+    /// Registered authorities are probed before other peers
+    Authority,
+}
+
+#[derive(Debug, Clone)]
+pub enum ProbeTarget {
+    /// Look up the peer's addresses in the DHT
+    Dht,
+    /// Dial all addresses concurrently, the first to connect wins
+    Direct(Vec<Multiaddr>),
+}
+
+#[derive(Debug)]
+pub struct QueuedProbe {
+    pub peer_id: PeerId,
+    pub target: ProbeTarget,
+    pub priority: ProbePriority,
+}
+
+/// Resolves to the result of a probe, which is also emitted as `BaseBehaviourEvent::PeerProbed`.
+/// Dropping the handle doesn't cancel the probe.
+#[derive(Debug)]
+pub struct ProbeHandle {
+    peer_id: PeerId,
+    rx: oneshot::Receiver<ProbeResult>,
+}
+
+impl ProbeHandle {
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+}
+
+impl Future for ProbeHandle {
+    type Output = ProbeResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.rx.poll_unpin(cx).map(|result| {
+            result.unwrap_or_else(|_| ProbeResult::Error {
+                kind: ProbeErrorKind::Cancelled,
+                message: "Behaviour dropped".into(),
+            })
+        })
+    }
+}
+
+/// Probes waiting for a free slot, highest priority first, and the handles of all
+/// queued and ongoing probes.
+pub struct ProbeQueue {
+    queue: VecDeque<QueuedProbe>,
+    max_queued: usize,
+    waiters: HashMap<PeerId, oneshot::Sender<ProbeResult>>,
+}
+
+impl ProbeQueue {
+    pub fn new(max_queued: usize) -> Self {
+        Self {
+            queue: Default::default(),
+            max_queued,
+            waiters: Default::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.queue.iter().any(|probe| probe.peer_id == *peer_id)
+    }
+
+    /// Create the handle of a probe, which is passed the result on `complete`.
+    pub fn watch(&mut self, peer_id: PeerId) -> ProbeHandle {
+        let (tx, rx) = oneshot::channel();
+        self.waiters.insert(peer_id, tx);
+        ProbeHandle { peer_id, rx }
+    }
+
+    /// Queue a probe which has to wait for a free slot.
+    pub fn push(
+        &mut self,
+        peer_id: PeerId,
+        target: ProbeTarget,
+        priority: ProbePriority,
+    ) -> Result<ProbeHandle, TryProbeError> {
+        if self.queue.len() >= self.max_queued {
+            return Err(TryProbeError::TooManyProbes);
+        }
+        // Behind all probes of the same or higher priority
+        let pos = self
+            .queue
+            .iter()
+            .position(|probe| probe.priority < priority)
+            .unwrap_or(self.queue.len());
+        self.queue.insert(
+            pos,
+            QueuedProbe {
+                peer_id,
+                target,
+                priority,
+            },
+        );
+        Ok(self.watch(peer_id))
+    }
+
+    pub fn pop(&mut self) -> Option<QueuedProbe> {
+        self.queue.pop_front()
+    }
+
+    /// Remove a probe which hasn't started yet.
+    pub fn remove(&mut self, peer_id: &PeerId) -> bool {
+        let len = self.queue.len();
+        self.queue.retain(|probe| probe.peer_id != *peer_id);
+        self.queue.len() != len
+    }
+
+    /// Pass the result to the probe's handle, if it's still there.
+    pub fn complete(&mut self, peer_id: &PeerId, result: &ProbeResult) {
+        if let Some(tx) = self.waiters.remove(peer_id) {
+            _ = tx.send(result.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_priorities() {
+        let mut queue = ProbeQueue::new(3);
+        let peers: Vec<_> = (0..4).map(|_| PeerId::random()).collect();
+        queue
+            .push(peers[0], ProbeTarget::Dht, ProbePriority::Normal)
+            .unwrap();
+        queue
+            .push(peers[1], ProbeTarget::Dht, ProbePriority::Authority)
+            .unwrap();
+        let handle = queue
+            .push(peers[2], ProbeTarget::Dht, ProbePriority::Authority)
+            .unwrap();
+        assert!(matches!(
+            queue.push(peers[3], ProbeTarget::Dht, ProbePriority::Authority),
+            Err(TryProbeError::TooManyProbes)
+        ));
+
+        assert!(queue.remove(&peers[1]));
+        assert!(!queue.contains(&peers[1]));
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|probe| probe.peer_id)
+            .collect();
+        assert_eq!(order, [peers[2], peers[0]]);
+
+        let result = ProbeResult::Error {
+            kind: ProbeErrorKind::Timeout,
+            message: "Probe timed out".into(),
+        };
+        queue.complete(&peers[2], &result);
+        assert!(matches!(
+            handle.await,
+            ProbeResult::Error {
+                kind: ProbeErrorKind::Timeout,
+                ..
+            }
+        ));
+    }
+}
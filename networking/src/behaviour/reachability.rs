@@ -0,0 +1,232 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    task::Context,
+    time::{Duration, Instant, SystemTime},
+};
+
+use libp2p::{Multiaddr, PeerId};
+use tokio::time::{interval_at, Interval, MissedTickBehavior};
+
+use super::base::{ProbeErrorKind, ProbeResult};
+use crate::utils::poll_ticks;
+
+/// Outcome of the latest probe of an authority.
+#[derive(Debug, Clone)]
+pub struct AuthorityReachability {
+    pub reachable: bool,
+    /// Agent version reported by the authority, if it was reached
+    pub agent_version: Option<Box<str>>,
+    /// Listen addresses reported by the authority, if it was reached
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Time from starting the probe until the authority identified itself, see
+    /// [`ProbeResult::Reachable`]
+    pub elapsed: Option<Duration>,
+    /// Why the authority couldn't be reached
+    pub error: Option<Box<str>>,
+    pub probed_at: SystemTime,
+}
+
+/// Reachability of all registered authorities, as of their latest probes.
+#[derive(Debug, Clone, Default)]
+pub struct ReachabilityMatrix {
+    pub authorities: HashMap<PeerId, AuthorityReachability>,
+}
+
+impl ReachabilityMatrix {
+    pub fn reachable_count(&self) -> usize {
+        self.authorities.values().filter(|r| r.reachable).count()
+    }
+
+    pub fn unreachable(&self) -> Vec<PeerId> {
+        self.authorities
+            .iter()
+            .filter(|(_, r)| !r.reachable)
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+}
+
+/// Result of recording a probe of a monitored authority.
+pub struct ProbeRecorded {
+    /// The authority was reached while previously it wasn't, or the other way round.
+    /// Also set on the first probe of an authority.
+    pub changed: bool,
+    /// This was the last probe of the current round.
+    pub round_finished: bool,
+}
+
+/// Probes all registered authorities in rounds, keeping the latest results.
+pub struct ReachabilityMonitor {
+    interval: Interval,
+    round_active: bool,
+    queue: VecDeque<PeerId>,
+    in_flight: HashSet<PeerId>,
+    matrix: ReachabilityMatrix,
+}
+
+impl ReachabilityMonitor {
+    pub fn new(period: Duration) -> Self {
+        // Probing right at startup would mostly fail, before the DHT is bootstrapped
+        let mut interval = interval_at((Instant::now() + period).into(), period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            interval,
+            round_active: false,
+            queue: Default::default(),
+            in_flight: Default::default(),
+            matrix: Default::default(),
+        }
+    }
+
+    pub fn matrix(&self) -> &ReachabilityMatrix {
+        &self.matrix
+    }
+
+    /// Start a new round when it's time for one and the previous round has finished.
+    pub fn poll_round(
+        &mut self,
+        cx: &mut Context<'_>,
+        registered: &HashSet<PeerId>,
+        local_peer_id: PeerId,
+    ) {
+        // Ticks during a round are skipped, but still polled to keep the waker registered
+        if !poll_ticks(&mut self.interval, cx) || self.round_active {
+            return;
+        }
+        self.matrix
+            .authorities
+            .retain(|peer_id, _| registered.contains(peer_id));
+        self.queue = registered
+            .iter()
+            .filter(|peer_id| **peer_id != local_peer_id)
+            .copied()
+            .collect();
+        self.round_active = !self.queue.is_empty();
+        log::debug!(
+            "Starting reachability round for {} authorities",
+            self.queue.len()
+        );
+    }
+
+    /// Next authority to probe in the current round.
+    pub fn next_peer(&mut self) -> Option<PeerId> {
+        self.queue.pop_front()
+    }
+
+    /// The probe couldn't be started yet and should be retried later.
+    pub fn requeue(&mut self, peer_id: PeerId) {
+        self.queue.push_front(peer_id);
+    }
+
+    pub fn probe_started(&mut self, peer_id: PeerId) {
+        self.in_flight.insert(peer_id);
+    }
+
+    /// Record the result, if the probe was started by the monitor. Cancelled probes say
+    /// nothing about the authority, its previous result is kept.
+    pub fn on_probe_result(
+        &mut self,
+        peer_id: PeerId,
+        result: &ProbeResult,
+    ) -> Option<ProbeRecorded> {
+        if !self.in_flight.remove(&peer_id) {
+            return None;
+        }
+        let changed = match result {
+            ProbeResult::Error {
+                kind: ProbeErrorKind::Cancelled,
+                ..
+            } => false,
+            _ => self.record(peer_id, result),
+        };
+
+        let round_finished =
+            self.round_active && self.queue.is_empty() && self.in_flight.is_empty();
+        if round_finished {
+            self.round_active = false;
+            log::info!(
+                "Reached {}/{} authorities, unreachable: {:?}",
+                self.matrix.reachable_count(),
+                self.matrix.authorities.len(),
+                self.matrix.unreachable()
+            );
+        }
+        Some(ProbeRecorded {
+            changed,
+            round_finished,
+        })
+    }
+
+    /// Whether the authority's reachability changed.
+    fn record(&mut self, peer_id: PeerId, result: &ProbeResult) -> bool {
+        let reachability = match result {
+            ProbeResult::Reachable {
+                listen_addrs,
+                agent_version,
+                elapsed,
+                ..
+            } => AuthorityReachability {
+                reachable: true,
+                agent_version: Some(agent_version.clone()),
+                listen_addrs: listen_addrs.clone(),
+                elapsed: Some(*elapsed),
+                error: None,
+                probed_at: SystemTime::now(),
+            },
+            ProbeResult::Error { message, .. } => unreachable_with(message.clone()),
+        };
+        let changed = self
+            .matrix
+            .authorities
+            .get(&peer_id)
+            .is_none_or(|prev| prev.reachable != reachability.reachable);
+        self.matrix.authorities.insert(peer_id, reachability);
+        changed
+    }
+}
+
+fn unreachable_with(error: Box<str>) -> AuthorityReachability {
+    AuthorityReachability {
+        reachable: false,
+        agent_version: None,
+        listen_addrs: vec![],
+        elapsed: None,
+        error: Some(error),
+        probed_at: SystemTime::now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(kind: ProbeErrorKind) -> ProbeResult {
+        ProbeResult::Error {
+            kind,
+            message: format!("{kind:?}").into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_probe_not_recorded() {
+        let mut monitor = ReachabilityMonitor::new(Duration::from_secs(60));
+        let peer_id = PeerId::random();
+
+        monitor.probe_started(peer_id);
+        let recorded = monitor.on_probe_result(peer_id, &error(ProbeErrorKind::Timeout));
+        assert!(recorded.unwrap().changed);
+        assert_eq!(monitor.matrix().unreachable(), [peer_id]);
+
+        // The previous result is kept
+        monitor.probe_started(peer_id);
+        let recorded = monitor.on_probe_result(peer_id, &error(ProbeErrorKind::Cancelled));
+        assert!(!recorded.unwrap().changed);
+        let reachability = &monitor.matrix().authorities[&peer_id];
+        assert_eq!(reachability.error.as_deref(), Some("Timeout"));
+
+        // Not counted as in flight anymore
+        assert!(monitor
+            .on_probe_result(peer_id, &error(ProbeErrorKind::Timeout))
+            .is_none());
+    }
+}
@@ -0,0 +1,178 @@
+use codec::{Decode, Encode};
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    kad::{Record, RecordKey},
+    PeerId,
+};
+
+/// DHT record value together with the signature of the publishing authority.
+#[derive(Debug, Clone, Encode, Decode)]
+struct SignedValue {
+    /// Increases with every record the publisher signs, so that older records
+    /// can't replace newer ones
+    seq: u64,
+    value: Vec<u8>,
+    /// Protobuf-encoded public key of the publisher
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+/// A verified DHT record.
+#[derive(Debug, Clone)]
+pub struct DhtRecord {
+    pub key: RecordKey,
+    pub publisher: PeerId,
+    pub seq: u64,
+    /// SCALE-encoded value
+    pub value: Vec<u8>,
+}
+
+impl DhtRecord {
+    pub fn decode<T: Decode>(&self) -> Result<T, codec::Error> {
+        T::decode(&mut &self.value[..])
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum InvalidRecord {
+    #[error("Malformed record value")]
+    Malformed,
+    #[error("Invalid publisher public key")]
+    InvalidPublicKey,
+    #[error("Record publisher doesn't match the signing key")]
+    PublisherMismatch,
+    #[error("Invalid record signature")]
+    InvalidSignature,
+    #[error("Publisher {0} is not a registered authority")]
+    NotRegistered(PeerId),
+    #[error("Record has sequence number {received}, older than the stored {stored}")]
+    Outdated { stored: u64, received: u64 },
+}
+
+fn signed_payload(key: &RecordKey, seq: u64, value: &[u8]) -> Vec<u8> {
+    (key.as_ref(), seq, value).encode()
+}
+
+/// Create a DHT record for `value`, signed with the local keypair. `seq` has to be
+/// higher than the one of any record previously published under `key`.
+pub fn sign_record(keypair: &Keypair, key: RecordKey, seq: u64, value: Vec<u8>) -> Record {
+    let signature = keypair
+        .sign(&signed_payload(&key, seq, &value))
+        .expect("signing with a local key should not fail");
+    let signed = SignedValue {
+        seq,
+        value,
+        public_key: keypair.public().encode_protobuf(),
+        signature,
+    };
+    let mut record = Record::new(key, signed.encode());
+    record.publisher = Some(keypair.public().to_peer_id());
+    record
+}
+
+/// Check that the record is signed by a registered authority.
+pub fn verify_record(
+    record: &Record,
+    is_registered: impl FnOnce(&PeerId) -> bool,
+) -> Result<DhtRecord, InvalidRecord> {
+    let signed =
+        SignedValue::decode(&mut &record.value[..]).map_err(|_| InvalidRecord::Malformed)?;
+    let public_key = PublicKey::try_decode_protobuf(&signed.public_key)
+        .map_err(|_| InvalidRecord::InvalidPublicKey)?;
+    let publisher = public_key.to_peer_id();
+    if record.publisher.is_some_and(|p| p != publisher) {
+        return Err(InvalidRecord::PublisherMismatch);
+    }
+    if !public_key.verify(
+        &signed_payload(&record.key, signed.seq, &signed.value),
+        &signed.signature,
+    ) {
+        return Err(InvalidRecord::InvalidSignature);
+    }
+    if !is_registered(&publisher) {
+        return Err(InvalidRecord::NotRegistered(publisher));
+    }
+    Ok(DhtRecord {
+        key: record.key.clone(),
+        publisher,
+        seq: signed.seq,
+        value: signed.value,
+    })
+}
+
+/// Reject a replayed record, which is older than the one stored under the same key.
+pub fn check_replay(record: &DhtRecord, stored: &Record) -> Result<(), InvalidRecord> {
+    let Ok(stored) = SignedValue::decode(&mut &stored.value[..]) else {
+        return Ok(());
+    };
+    check_seq(record.seq, stored.seq)
+}
+
+/// Reject a record which is older than `other`, in the same order as [`check_replay`].
+pub fn check_newer(record: &DhtRecord, other: &DhtRecord) -> Result<(), InvalidRecord> {
+    check_seq(record.seq, other.seq)
+}
+
+fn check_seq(received: u64, stored: u64) -> Result<(), InvalidRecord> {
+    if received < stored {
+        return Err(InvalidRecord::Outdated { stored, received });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_verification() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let key = RecordKey::new(b"checkpoint");
+        let record = sign_record(&keypair, key.clone(), 2, 42u64.encode());
+
+        let verified = verify_record(&record, |p| *p == peer_id).unwrap();
+        assert_eq!(verified.publisher, peer_id);
+        assert_eq!(verified.seq, 2);
+        assert_eq!(verified.decode::<u64>().unwrap(), 42);
+
+        // An older record of the same publisher can't replace the stored one
+        let older = sign_record(&keypair, key.clone(), 1, 41u64.encode());
+        let older = verify_record(&older, |_| true).unwrap();
+        assert_eq!(
+            check_replay(&older, &record).unwrap_err(),
+            InvalidRecord::Outdated {
+                stored: 2,
+                received: 1
+            }
+        );
+        assert!(check_replay(&verified, &record).is_ok());
+
+        assert_eq!(
+            verify_record(&record, |_| false).unwrap_err(),
+            InvalidRecord::NotRegistered(peer_id)
+        );
+
+        // Moving the value under a different key invalidates the signature
+        let mut moved = record.clone();
+        moved.key = RecordKey::new(b"other");
+        assert_eq!(
+            verify_record(&moved, |_| true).unwrap_err(),
+            InvalidRecord::InvalidSignature
+        );
+
+        let mut spoofed = record.clone();
+        spoofed.publisher = Some(PeerId::random());
+        assert_eq!(
+            verify_record(&spoofed, |_| true).unwrap_err(),
+            InvalidRecord::PublisherMismatch
+        );
+
+        let mut malformed = record;
+        malformed.value = vec![1, 2, 3];
+        assert_eq!(
+            verify_record(&malformed, |_| true).unwrap_err(),
+            InvalidRecord::Malformed
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use codec::{Decode, Encode};
+use libp2p::{
+    kad::{
+        store::{self, MemoryStore, MemoryStoreConfig, RecordStore},
+        KBucketKey, ProviderRecord, Record, RecordKey,
+    },
+    Multiaddr, PeerId,
+};
+
+const RECORDS_DIR: &str = "records";
+const PROVIDERS_DIR: &str = "providers";
+
+/// Kademlia record store which keeps a copy of every record and provider entry on disk,
+/// so they survive restarts.
+///
+/// Limits are enforced by the wrapped [`MemoryStore`]. Without a data directory,
+/// it behaves exactly like a [`MemoryStore`]. Files are written by a background thread,
+/// so the swarm is never blocked on disk I/O.
+pub struct PersistentStore {
+    memory: MemoryStore,
+    dir: Option<PathBuf>,
+    writer: Option<FileWriter>,
+}
+
+/// Contents to write to a file, `None` removes it.
+type FileOp = (PathBuf, Option<Vec<u8>>);
+
+/// Background thread applying file writes in batches. Writes queued for the same file
+/// are coalesced, only the latest contents reach the disk. Dropping the writer waits
+/// until all queued writes are done.
+struct FileWriter {
+    sender: Option<mpsc::Sender<FileOp>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl FileWriter {
+    fn spawn() -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<FileOp>();
+        let thread = thread::Builder::new()
+            .name("dht-store-writer".to_string())
+            .spawn(move || {
+                while let Ok(op) = receiver.recv() {
+                    let batch: HashMap<_, _> =
+                        std::iter::once(op).chain(receiver.try_iter()).collect();
+                    for (path, contents) in batch {
+                        match contents {
+                            Some(contents) => write_file(&path, &contents),
+                            None => remove_file(&path),
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    fn send(&self, path: PathBuf, contents: Option<Vec<u8>>) {
+        if let Some(sender) = &self.sender {
+            if sender.send((path, contents)).is_err() {
+                log::error!("DHT store writer thread has stopped");
+            }
+        }
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread finish once the queue is drained
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Encode, Decode)]
+struct StoredRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    publisher: Option<Vec<u8>>,
+    /// Expiration time in milliseconds since UNIX epoch
+    expires: Option<u64>,
+}
+
+#[derive(Encode, Decode)]
+struct StoredProvider {
+    provider: Vec<u8>,
+    expires: Option<u64>,
+    addresses: Vec<Vec<u8>>,
+}
+
+#[derive(Encode, Decode)]
+struct StoredProviders {
+    key: Vec<u8>,
+    providers: Vec<StoredProvider>,
+}
+
+impl PersistentStore {
+    pub fn in_memory(local_peer_id: PeerId, config: MemoryStoreConfig) -> Self {
+        Self {
+            memory: MemoryStore::with_config(local_peer_id, config),
+            dir: None,
+            writer: None,
+        }
+    }
+
+    /// Open the store in `dir`, loading all unexpired records and provider entries.
+    pub fn open(
+        local_peer_id: PeerId,
+        config: MemoryStoreConfig,
+        dir: impl Into<PathBuf>,
+    ) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(RECORDS_DIR))?;
+        fs::create_dir_all(dir.join(PROVIDERS_DIR))?;
+        let mut memory = MemoryStore::with_config(local_peer_id, config);
+        let (mut records, mut providers) = (0, 0);
+        for path in list_files(&dir.join(RECORDS_DIR))? {
+            // Records which expired while the node was down are deleted, like unreadable ones
+            match read_record(&path) {
+                Some(record) if !record.is_expired(Instant::now()) => match memory.put(record) {
+                    Ok(()) => records += 1,
+                    Err(e) => log::warn!("Dropping stored record {}: {e}", path.display()),
+                },
+                _ => remove_file(&path),
+            }
+        }
+        for path in list_files(&dir.join(PROVIDERS_DIR))? {
+            let entries = read_providers(&path).unwrap_or_default();
+            let entries: Vec<_> = entries
+                .into_iter()
+                .filter(|p| !p.is_expired(Instant::now()))
+                .collect();
+            if entries.is_empty() {
+                remove_file(&path);
+            }
+            for entry in entries {
+                match memory.add_provider(entry) {
+                    Ok(()) => providers += 1,
+                    Err(e) => log::warn!("Dropping stored provider {}: {e}", path.display()),
+                }
+            }
+        }
+        log::info!(
+            "Loaded {records} DHT records and {providers} provider entries from {}",
+            dir.display()
+        );
+
+        Ok(Self {
+            memory,
+            dir: Some(dir),
+            writer: Some(FileWriter::spawn()?),
+        })
+    }
+
+    fn path(&self, subdir: &str, key: &RecordKey) -> Option<PathBuf> {
+        // Keys can be arbitrarily long, their hashes make for valid file names
+        let hash = KBucketKey::new(key.clone());
+        let name: String = hash
+            .hashed_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        self.dir.as_ref().map(|dir| dir.join(subdir).join(name))
+    }
+
+    fn write(&self, path: PathBuf, contents: Option<Vec<u8>>) {
+        if let Some(writer) = &self.writer {
+            writer.send(path, contents);
+        }
+    }
+
+    fn save_providers(&self, key: &RecordKey) {
+        let Some(path) = self.path(PROVIDERS_DIR, key) else {
+            return;
+        };
+        let providers = self.memory.providers(key);
+        if providers.is_empty() {
+            self.write(path, None);
+            return;
+        }
+        let stored = StoredProviders {
+            key: key.to_vec(),
+            providers: providers
+                .into_iter()
+                .map(|p| StoredProvider {
+                    provider: p.provider.to_bytes(),
+                    expires: p.expires.map(to_unix_millis),
+                    addresses: p.addresses.into_iter().map(|a| a.to_vec()).collect(),
+                })
+                .collect(),
+        };
+        self.write(path, Some(stored.encode()));
+    }
+}
+
+impl RecordStore for PersistentStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.memory.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> store::Result<()> {
+        let path = self.path(RECORDS_DIR, &r.key);
+        let stored = path.is_some().then(|| StoredRecord {
+            key: r.key.to_vec(),
+            value: r.value.clone(),
+            publisher: r.publisher.map(|p| p.to_bytes()),
+            expires: r.expires.map(to_unix_millis),
+        });
+        self.memory.put(r)?;
+        if let (Some(path), Some(stored)) = (path, stored) {
+            self.write(path, Some(stored.encode()));
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &RecordKey) {
+        self.memory.remove(k);
+        if let Some(path) = self.path(RECORDS_DIR, k) {
+            self.write(path, None);
+        }
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.memory.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> store::Result<()> {
+        let key = record.key.clone();
+        self.memory.add_provider(record)?;
+        self.save_providers(&key);
+        Ok(())
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        self.memory.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.memory.provided()
+    }
+
+    fn remove_provider(&mut self, k: &RecordKey, p: &PeerId) {
+        self.memory.remove_provider(k, p);
+        self.save_providers(k);
+    }
+}
+
+fn to_unix_millis(instant: Instant) -> u64 {
+    let now = SystemTime::now();
+    let time = match instant.checked_duration_since(Instant::now()) {
+        Some(remaining) => now + remaining,
+        None => now,
+    };
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Times in the past map to now, so that entries which expired while the node was down
+/// count as expired.
+fn from_unix_millis(millis: u64) -> Instant {
+    let time = UNIX_EPOCH + Duration::from_millis(millis);
+    let remaining = time.duration_since(SystemTime::now()).unwrap_or_default();
+    Instant::now() + remaining
+}
+
+fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+fn read_record(path: &Path) -> Option<Record> {
+    let bytes = fs::read(path).ok()?;
+    let stored = StoredRecord::decode(&mut &bytes[..]).ok()?;
+    let publisher = match stored.publisher {
+        Some(bytes) => Some(PeerId::from_bytes(&bytes).ok()?),
+        None => None,
+    };
+    Some(Record {
+        key: RecordKey::from(stored.key),
+        value: stored.value,
+        publisher,
+        expires: stored.expires.map(from_unix_millis),
+    })
+}
+
+fn read_providers(path: &Path) -> Option<Vec<ProviderRecord>> {
+    let bytes = fs::read(path).ok()?;
+    let stored = StoredProviders::decode(&mut &bytes[..]).ok()?;
+    let key = RecordKey::from(stored.key);
+    stored
+        .providers
+        .into_iter()
+        .map(|p| {
+            Some(ProviderRecord {
+                key: key.clone(),
+                provider: PeerId::from_bytes(&p.provider).ok()?,
+                expires: p.expires.map(from_unix_millis),
+                addresses: p
+                    .addresses
+                    .into_iter()
+                    .filter_map(|a| Multiaddr::try_from(a).ok())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Write the file atomically, so that a crash doesn't leave a truncated record behind.
+fn write_file(path: &Path, contents: &[u8]) {
+    let tmp_path = path.with_extension("tmp");
+    if let Err(e) = fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path)) {
+        log::warn!("Couldn't write {}: {e}", path.display());
+    }
+}
+
+fn remove_file(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => log::warn!("Couldn't remove {}: {e}", path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persistent_store() {
+        let dir = std::env::temp_dir().join(format!("kad-store-{}", rand::random::<u64>()));
+        let local_peer_id = PeerId::random();
+        let provider = PeerId::random();
+        let key = RecordKey::new(b"checkpoint");
+        let expired_key = RecordKey::new(b"expired");
+
+        let mut store = PersistentStore::open(local_peer_id, Default::default(), &dir).unwrap();
+        let mut record = Record::new(key.clone(), vec![1, 2, 3]);
+        record.expires = Some(Instant::now() + Duration::from_secs(3600));
+        store.put(record.clone()).unwrap();
+        let mut expired = Record::new(expired_key.clone(), vec![4]);
+        expired.expires = Some(Instant::now());
+        store.put(expired).unwrap();
+        store
+            .add_provider(ProviderRecord::new(
+                key.clone(),
+                provider,
+                vec!["/memory/1".parse().unwrap()],
+            ))
+            .unwrap();
+
+        // Too large values are rejected, like by `MemoryStore`
+        let config = MemoryStoreConfig {
+            max_value_bytes: 2,
+            ..Default::default()
+        };
+        let mut limited = PersistentStore::in_memory(local_peer_id, config);
+        assert!(matches!(
+            limited.put(record.clone()),
+            Err(store::Error::ValueTooLarge)
+        ));
+
+        // Wait for the queued writes
+        drop(store);
+        let reopened = PersistentStore::open(local_peer_id, Default::default(), &dir).unwrap();
+        let loaded = reopened.get(&key).unwrap();
+        assert_eq!(loaded.value, record.value);
+        assert!(!loaded.is_expired(Instant::now() + Duration::from_secs(3500)));
+        assert!(reopened.get(&expired_key).is_none());
+        let providers = reopened.providers(&key);
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].provider, provider);
+        assert_eq!(providers[0].addresses, ["/memory/1".parse().unwrap()]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
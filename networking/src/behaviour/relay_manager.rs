@@ -0,0 +1,427 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::Infallible,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use libp2p::{
+    core::transport::ListenerId,
+    multiaddr::Protocol,
+    swarm::{ListenOpts, ToSwarm},
+    Multiaddr, PeerId,
+};
+use tokio::time::{interval, Instant, Interval, MissedTickBehavior};
+
+use crate::utils::poll_ticks;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RelayManagerConfig {
+    /// Number of relays to hold reservations on at the same time.
+    pub reservations: usize,
+    /// Delay before retrying a relay which failed or refused a reservation.
+    pub retry_interval: Duration,
+    /// Only hold reservations while the node isn't publicly reachable.
+    pub auto: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationState {
+    Inactive,
+    Pending,
+    Active,
+}
+
+/// Reservation state and history of a candidate relay.
+#[derive(Debug, Clone)]
+pub struct RelayReservation {
+    pub relay_peer_id: PeerId,
+    pub addr: Multiaddr,
+    pub state: ReservationState,
+    /// Number of reservations the relay accepted
+    pub successes: u32,
+    /// Number of reservations the relay refused or lost
+    pub failures: u32,
+}
+
+struct Candidate {
+    reservation: RelayReservation,
+    listener: Option<ListenerId>,
+    retry_at: Option<Instant>,
+}
+
+impl Candidate {
+    fn score(&self) -> i64 {
+        self.reservation.successes as i64 - 2 * self.reservation.failures as i64
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        self.listener.is_none() && self.retry_at.is_none_or(|t| t <= now)
+    }
+}
+
+/// Keeps reservations on a number of relays, picking them by success history and latency,
+/// and failing over to other relays when one refuses or drops a reservation.
+///
+/// Reservations are renewed by the relay client as long as the listener is open.
+pub struct RelayManager {
+    config: RelayManagerConfig,
+    candidates: Vec<Candidate>,
+    enabled: bool,
+    needs_update: bool,
+    /// Circuit addresses confirmed by the relay client
+    circuit_addrs: HashSet<Multiaddr>,
+    retry_interval: Interval,
+    pending_events: VecDeque<ToSwarm<RelayReservation, Infallible>>,
+}
+
+impl RelayManager {
+    /// `relay_addrs` have to end with `/p2p/<relay_peer_id>`, others are ignored.
+    pub fn new(relay_addrs: Vec<Multiaddr>, config: RelayManagerConfig) -> Self {
+        let candidates = relay_addrs
+            .into_iter()
+            .filter_map(|addr| match addr.iter().last() {
+                Some(Protocol::P2p(relay_peer_id)) => Some(Candidate {
+                    reservation: RelayReservation {
+                        relay_peer_id,
+                        addr,
+                        state: ReservationState::Inactive,
+                        successes: 0,
+                        failures: 0,
+                    },
+                    listener: None,
+                    retry_at: None,
+                }),
+                _ => {
+                    log::warn!("Ignoring relay address without peer ID: {addr}");
+                    None
+                }
+            })
+            .collect();
+        let mut retry_interval = interval(config.retry_interval);
+        retry_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            config,
+            candidates,
+            enabled: !config.auto,
+            needs_update: true,
+            circuit_addrs: Default::default(),
+            retry_interval,
+            pending_events: Default::default(),
+        }
+    }
+
+    pub fn reservations(&self) -> Vec<RelayReservation> {
+        self.candidates
+            .iter()
+            .map(|c| c.reservation.clone())
+            .collect()
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.candidates.iter().any(|c| c.listener.is_some())
+    }
+
+    /// The node turned out to be private. With auto relay, reservations are made now.
+    pub fn on_private(&mut self) {
+        if self.config.auto && !self.enabled {
+            log::info!("Not publicly reachable, making relay reservations");
+            self.enabled = true;
+            self.needs_update = true;
+        }
+    }
+
+    /// The node turned out to be public. With auto relay, the reservations are released
+    /// and circuit addresses are no longer advertised.
+    pub fn on_public(&mut self) {
+        if !self.config.auto || !self.enabled {
+            return;
+        }
+        log::info!("Publicly reachable, releasing relay reservations");
+        self.enabled = false;
+        for i in 0..self.candidates.len() {
+            self.release(i);
+        }
+    }
+
+    pub fn on_reservation_accepted(&mut self, relay_peer_id: PeerId, renewal: bool) {
+        let Some(i) =
+            self.find(|c| c.reservation.relay_peer_id == relay_peer_id && c.listener.is_some())
+        else {
+            return;
+        };
+        if renewal {
+            log::debug!("Reservation on relay {relay_peer_id} renewed");
+        }
+        let candidate = &mut self.candidates[i];
+        if candidate.reservation.state != ReservationState::Active {
+            log::info!("Reservation on relay {relay_peer_id} accepted");
+            candidate.reservation.successes += 1;
+            self.set_state(i, ReservationState::Active);
+        }
+    }
+
+    /// The relay listener closed or failed. Another relay is tried instead.
+    pub fn on_listener_failed(&mut self, listener_id: ListenerId, reason: &str) {
+        let Some(i) = self.find(|c| c.listener == Some(listener_id)) else {
+            return;
+        };
+        log::warn!(
+            "Reservation on relay {} failed: {reason}",
+            self.candidates[i].reservation.relay_peer_id
+        );
+        self.candidates[i].listener = None;
+        self.on_failure(i);
+    }
+
+    /// All connections to the peer closed. If it's a relay, the reservation is lost.
+    pub fn on_disconnected(&mut self, peer_id: PeerId) {
+        let Some(i) = self.find(|c| c.reservation.relay_peer_id == peer_id && c.listener.is_some())
+        else {
+            return;
+        };
+        log::warn!("Disconnected from relay {peer_id}");
+        if let Some(id) = self.candidates[i].listener.take() {
+            self.pending_events
+                .push_back(ToSwarm::RemoveListener { id });
+        }
+        self.on_failure(i);
+    }
+
+    pub fn on_external_addr_confirmed(&mut self, addr: &Multiaddr) {
+        if addr.iter().any(|p| p == Protocol::P2pCircuit) {
+            self.circuit_addrs.insert(addr.clone());
+        }
+    }
+
+    pub fn on_external_addr_expired(&mut self, addr: &Multiaddr) {
+        self.circuit_addrs.remove(addr);
+    }
+
+    /// Make reservations to replace failed ones or when enabled.
+    /// `latency` gives the round-trip time to a relay, if it's known.
+    pub fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        latency: impl Fn(&PeerId) -> Option<Duration>,
+    ) -> Poll<ToSwarm<RelayReservation, Infallible>> {
+        if poll_ticks(&mut self.retry_interval, cx) {
+            self.needs_update = true;
+        }
+        if self.needs_update {
+            self.needs_update = false;
+            self.make_reservations(Instant::now(), latency);
+        }
+        match self.pending_events.pop_front() {
+            Some(ev) => Poll::Ready(ev),
+            None => Poll::Pending,
+        }
+    }
+
+    fn make_reservations(&mut self, now: Instant, latency: impl Fn(&PeerId) -> Option<Duration>) {
+        if !self.enabled {
+            return;
+        }
+        let held = self
+            .candidates
+            .iter()
+            .filter(|c| c.listener.is_some())
+            .count();
+        let missing = self.config.reservations.saturating_sub(held);
+        let mut available: Vec<_> = (0..self.candidates.len())
+            .filter(|i| self.candidates[*i].is_available(now))
+            .collect();
+        // Best history first, then lowest latency; relays with unknown latency go last
+        available.sort_by_key(|i| {
+            let candidate = &self.candidates[*i];
+            let rtt = latency(&candidate.reservation.relay_peer_id);
+            (-candidate.score(), rtt.is_none(), rtt)
+        });
+        for i in available.into_iter().take(missing) {
+            let addr = self.candidates[i]
+                .reservation
+                .addr
+                .clone()
+                .with(Protocol::P2pCircuit);
+            log::info!("Listening on relay {addr}");
+            let opts = ListenOpts::new(addr);
+            self.candidates[i].listener = Some(opts.listener_id());
+            self.pending_events.push_back(ToSwarm::ListenOn { opts });
+            self.set_state(i, ReservationState::Pending);
+        }
+    }
+
+    fn on_failure(&mut self, i: usize) {
+        self.candidates[i].reservation.failures += 1;
+        self.candidates[i].retry_at = Some(Instant::now() + self.config.retry_interval);
+        self.expire_circuit_addrs(i);
+        self.set_state(i, ReservationState::Inactive);
+        self.needs_update = true;
+    }
+
+    fn release(&mut self, i: usize) {
+        if let Some(id) = self.candidates[i].listener.take() {
+            self.pending_events
+                .push_back(ToSwarm::RemoveListener { id });
+        }
+        self.expire_circuit_addrs(i);
+        self.set_state(i, ReservationState::Inactive);
+    }
+
+    fn expire_circuit_addrs(&mut self, i: usize) {
+        let relay_peer_id = self.candidates[i].reservation.relay_peer_id;
+        let expired: Vec<_> = self
+            .circuit_addrs
+            .iter()
+            .filter(|addr| addr.iter().any(|p| p == Protocol::P2p(relay_peer_id)))
+            .cloned()
+            .collect();
+        for addr in expired {
+            self.circuit_addrs.remove(&addr);
+            self.pending_events
+                .push_back(ToSwarm::ExternalAddrExpired(addr));
+        }
+    }
+
+    fn set_state(&mut self, i: usize, state: ReservationState) {
+        let reservation = &mut self.candidates[i].reservation;
+        if reservation.state != state {
+            reservation.state = state;
+            self.pending_events
+                .push_back(ToSwarm::GenerateEvent(reservation.clone()));
+        }
+    }
+
+    fn find(&self, f: impl Fn(&Candidate) -> bool) -> Option<usize> {
+        self.candidates.iter().position(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::task::noop_waker_ref;
+
+    use super::*;
+
+    fn relay_addr(port: u16) -> (PeerId, Multiaddr) {
+        let peer_id = PeerId::random();
+        let addr = format!("/memory/{port}/p2p/{peer_id}").parse().unwrap();
+        (peer_id, addr)
+    }
+
+    fn poll_events(
+        manager: &mut RelayManager,
+        latency: impl Fn(&PeerId) -> Option<Duration> + Copy,
+    ) -> Vec<ToSwarm<RelayReservation, Infallible>> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        std::iter::from_fn(|| match manager.poll(&mut cx, latency) {
+            Poll::Ready(ev) => Some(ev),
+            Poll::Pending => None,
+        })
+        .collect()
+    }
+
+    fn listen_ids(
+        events: &[ToSwarm<RelayReservation, Infallible>],
+    ) -> Vec<(ListenerId, Multiaddr)> {
+        events
+            .iter()
+            .filter_map(|ev| match ev {
+                ToSwarm::ListenOn { opts } => Some((opts.listener_id(), opts.address().clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let relays: Vec<_> = (1..=3).map(relay_addr).collect();
+        let config = RelayManagerConfig {
+            reservations: 2,
+            retry_interval: Duration::from_secs(60),
+            auto: false,
+        };
+        let mut manager = RelayManager::new(relays.iter().map(|r| r.1.clone()).collect(), config);
+        // The third relay has the lowest latency, the second one is unknown
+        let latency = |peer_id: &PeerId| {
+            (*peer_id != relays[1].0)
+                .then(|| Duration::from_millis(if *peer_id == relays[2].0 { 10 } else { 50 }))
+        };
+
+        let events = poll_events(&mut manager, latency);
+        let listening = listen_ids(&events);
+        assert_eq!(listening.len(), 2);
+        assert_eq!(
+            listening[0].1,
+            relays[2].1.clone().with(Protocol::P2pCircuit)
+        );
+        assert_eq!(
+            listening[1].1,
+            relays[0].1.clone().with(Protocol::P2pCircuit)
+        );
+
+        manager.on_reservation_accepted(relays[2].0, false);
+        manager.on_reservation_accepted(relays[0].0, false);
+        let states: Vec<_> = manager.reservations().iter().map(|r| r.state).collect();
+        use ReservationState::*;
+        assert_eq!(states, [Active, Inactive, Active]);
+
+        // Losing a relay makes the manager fail over to the remaining one
+        let circuit = relays[2]
+            .1
+            .clone()
+            .with(Protocol::P2pCircuit)
+            .with(Protocol::P2p(PeerId::random()));
+        manager.on_external_addr_confirmed(&circuit);
+        manager.on_disconnected(relays[2].0);
+        let events = poll_events(&mut manager, latency);
+        assert!(events
+            .iter()
+            .any(|ev| matches!(ev, ToSwarm::RemoveListener { id } if *id == listening[0].0)));
+        assert!(events
+            .iter()
+            .any(|ev| matches!(ev, ToSwarm::ExternalAddrExpired(addr) if *addr == circuit)));
+        let failover = listen_ids(&events);
+        assert_eq!(failover.len(), 1);
+        assert_eq!(
+            failover[0].1,
+            relays[1].1.clone().with(Protocol::P2pCircuit)
+        );
+
+        // The failed relay isn't retried right away
+        manager.on_listener_failed(failover[0].0, "Reservation refused");
+        assert!(listen_ids(&poll_events(&mut manager, latency)).is_empty());
+        let reservation = &manager.reservations()[2];
+        assert_eq!(reservation.failures, 1);
+        assert_eq!(reservation.successes, 1);
+    }
+
+    #[tokio::test]
+    async fn test_auto_relay() {
+        let (_, relay) = relay_addr(1);
+        let config = RelayManagerConfig {
+            reservations: 2,
+            retry_interval: Duration::from_secs(60),
+            auto: true,
+        };
+        let mut manager = RelayManager::new(vec![relay.clone()], config);
+        let no_latency = |_: &PeerId| None;
+        assert!(poll_events(&mut manager, no_latency).is_empty());
+
+        manager.on_private();
+        let listening = listen_ids(&poll_events(&mut manager, no_latency));
+        assert_eq!(listening.len(), 1);
+        assert!(manager.is_listening());
+
+        manager.on_public();
+        let events = poll_events(&mut manager, no_latency);
+        assert!(matches!(
+            &events[..],
+            [ToSwarm::RemoveListener { id }, ToSwarm::GenerateEvent(r)]
+                if *id == listening[0].0 && r.state == ReservationState::Inactive
+        ));
+        assert!(!manager.is_listening());
+        // Releasing reservations doesn't count as failure
+        assert_eq!(manager.reservations()[0].failures, 0);
+    }
+}
@@ -0,0 +1,109 @@
+use std::{collections::HashSet, sync::Arc, time::Duration, time::Instant};
+
+use libp2p::{
+    relay::{self, RateLimiter},
+    Multiaddr, PeerId,
+};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationSeconds};
+
+use crate::config::EnvReader;
+
+#[serde_as]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayServerConfig {
+    /// Maximum number of active reservations (default: 128).
+    pub max_reservations: usize,
+    /// Maximum number of active reservations of a single peer (default: 4).
+    pub max_reservations_per_peer: usize,
+    /// How long a reservation is valid before it has to be renewed (default: 1 hour).
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub reservation_duration: Duration,
+    /// Maximum number of relayed connections (default: 16).
+    pub max_circuits: usize,
+    /// Maximum number of relayed connections to or from a single peer (default: 4).
+    pub max_circuits_per_peer: usize,
+    /// Time after which a relayed connection is closed (default: 2 min).
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub max_circuit_duration: Duration,
+    /// Number of bytes after which a relayed connection is closed (default: 128 KiB).
+    pub max_circuit_bytes: u64,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        Self {
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_duration: Duration::from_secs(3600),
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration: Duration::from_secs(120),
+            max_circuit_bytes: 1 << 17,
+        }
+    }
+}
+
+impl RelayServerConfig {
+    /// Override the settings given in the environment.
+    pub fn apply_env(&mut self, env: &mut EnvReader) {
+        env.var("RELAY_MAX_RESERVATIONS", &mut self.max_reservations);
+        env.var(
+            "RELAY_MAX_RESERVATIONS_PER_PEER",
+            &mut self.max_reservations_per_peer,
+        );
+        env.secs(
+            "RELAY_RESERVATION_DURATION_SEC",
+            &mut self.reservation_duration,
+        );
+        env.var("RELAY_MAX_CIRCUITS", &mut self.max_circuits);
+        env.var(
+            "RELAY_MAX_CIRCUITS_PER_PEER",
+            &mut self.max_circuits_per_peer,
+        );
+        env.secs(
+            "RELAY_MAX_CIRCUIT_DURATION_SEC",
+            &mut self.max_circuit_duration,
+        );
+        env.var("RELAY_MAX_CIRCUIT_BYTES", &mut self.max_circuit_bytes);
+    }
+}
+
+/// Only lets registered authorities make reservations.
+struct AuthorityLimiter {
+    registered_nodes: Arc<RwLock<HashSet<PeerId>>>,
+}
+
+impl RateLimiter for AuthorityLimiter {
+    fn try_next(&mut self, peer: PeerId, _addr: &Multiaddr, _now: Instant) -> bool {
+        let allowed = self.registered_nodes.read().contains(&peer);
+        if !allowed {
+            log::debug!("Denying relay reservation for non-authority {peer}");
+        }
+        allowed
+    }
+}
+
+pub fn relay_server(
+    local_peer_id: PeerId,
+    config: RelayServerConfig,
+    registered_nodes: Arc<RwLock<HashSet<PeerId>>>,
+) -> relay::Behaviour {
+    let mut relay_config = relay::Config {
+        max_reservations: config.max_reservations,
+        max_reservations_per_peer: config.max_reservations_per_peer,
+        reservation_duration: config.reservation_duration,
+        max_circuits: config.max_circuits,
+        max_circuits_per_peer: config.max_circuits_per_peer,
+        max_circuit_duration: config.max_circuit_duration,
+        max_circuit_bytes: config.max_circuit_bytes,
+        ..Default::default()
+    };
+    // Checked first, so that the remaining rate limiters only count authorities
+    relay_config
+        .reservation_rate_limiters
+        .insert(0, Box::new(AuthorityLimiter { registered_nodes }));
+    relay::Behaviour::new(local_peer_id, relay_config)
+}
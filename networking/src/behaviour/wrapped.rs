@@ -34,6 +34,15 @@ pub trait BehaviourWrapper {
     fn poll(&mut self, _cx: &mut Context<'_>) -> Poll<impl IntoIterator<Item = TToSwarm<Self>>> {
         Poll::<Vec<_>>::Pending
     }
+    /// Filter the addresses the inner behaviour provides for an outbound dial.
+    fn filter_dial_addresses(
+        &mut self,
+        _connection_id: ConnectionId,
+        _maybe_peer: Option<PeerId>,
+        addresses: Vec<Multiaddr>,
+    ) -> Vec<Multiaddr> {
+        addresses
+    }
 }
 
 pub struct Wrapped<T: BehaviourWrapper + 'static> {
@@ -104,12 +113,15 @@ where
         addresses: &[Multiaddr],
         effective_role: Endpoint,
     ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
-        self.inner().handle_pending_outbound_connection(
+        let addresses = self.inner().handle_pending_outbound_connection(
             connection_id,
             maybe_peer,
             addresses,
             effective_role,
-        )
+        )?;
+        Ok(self
+            .wrapper
+            .filter_dial_addresses(connection_id, maybe_peer, addresses))
     }
 
     fn handle_established_outbound_connection(
@@ -0,0 +1,238 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::{Display, Formatter},
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use hickory_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    TokioAsyncResolver,
+};
+use libp2p::{multiaddr::Protocol, Multiaddr};
+
+use crate::cli::BootNode;
+
+/// Prefix of the domain holding the TXT records of a `/dnsaddr`.
+const DNSADDR_PREFIX: &str = "_dnsaddr.";
+/// Maximum number of DNS lookups when resolving a single `/dnsaddr`, which may be nested.
+const MAX_DNS_LOOKUPS: usize = 32;
+
+/// Boot node as configured: either a single node, or a `/dnsaddr` name listing many.
+#[derive(Debug, Clone)]
+pub enum BootNodeSource {
+    Node(BootNode),
+    /// `/dnsaddr/<domain>`, optionally followed by `/p2p/<peer_id>` to pick a single node.
+    DnsAddr(Multiaddr),
+}
+
+impl FromStr for BootNodeSource {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.starts_with("/dnsaddr/") {
+            let address = s.parse().map_err(|_| "Invalid address")?;
+            return Ok(Self::DnsAddr(address));
+        }
+        s.parse().map(Self::Node)
+    }
+}
+
+impl Display for BootNodeSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Node(node) => node.fmt(f),
+            Self::DnsAddr(address) => address.fmt(f),
+        }
+    }
+}
+
+impl From<BootNode> for BootNodeSource {
+    fn from(node: BootNode) -> Self {
+        Self::Node(node)
+    }
+}
+
+/// Read boot nodes from a file with one entry per line. Empty lines and lines
+/// starting with `#` are skipped.
+pub fn read_boot_nodes_file(path: &Path) -> io::Result<Vec<BootNodeSource>> {
+    fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            line.parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {e}", path.display(), i + 1),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Turns the configured boot node sources into boot nodes. The file and DNS records
+/// are read again on every call, so that changes are picked up on refresh.
+#[derive(Clone)]
+pub struct BootNodeResolver {
+    sources: Vec<BootNodeSource>,
+    file: Option<PathBuf>,
+    /// Only created if there is anything to look up, i.e. the resolver is dynamic.
+    resolver: Option<TokioAsyncResolver>,
+}
+
+/// Boot nodes given by [`BootNodeResolver::resolve_all`].
+pub struct ResolvedBootNodes {
+    pub boot_nodes: Vec<BootNode>,
+    /// Whether every source could be read. Otherwise boot nodes may be missing
+    /// only because of a temporary failure.
+    pub complete: bool,
+}
+
+impl BootNodeResolver {
+    /// Resolve with the system DNS configuration, which is only read if there is a `/dnsaddr`
+    /// source or a boot nodes file.
+    pub fn new(sources: Vec<BootNodeSource>, file: Option<PathBuf>) -> Result<Self, ResolveError> {
+        let mut resolver = Self {
+            sources,
+            file,
+            resolver: None,
+        };
+        if resolver.is_dynamic() {
+            resolver.resolver = Some(TokioAsyncResolver::tokio_from_system_conf()?);
+        }
+        Ok(resolver)
+    }
+
+    pub fn with_config(
+        sources: Vec<BootNodeSource>,
+        file: Option<PathBuf>,
+        config: ResolverConfig,
+        opts: ResolverOpts,
+    ) -> Self {
+        Self {
+            sources,
+            file,
+            resolver: Some(TokioAsyncResolver::tokio(config, opts)),
+        }
+    }
+
+    /// Whether resolving again may give different boot nodes.
+    pub fn is_dynamic(&self) -> bool {
+        self.file.is_some()
+            || self
+                .sources
+                .iter()
+                .any(|s| matches!(s, BootNodeSource::DnsAddr(_)))
+    }
+
+    /// Resolve all sources. Failures are logged and skipped, so that one unavailable
+    /// source doesn't prevent connecting to the others.
+    pub async fn resolve(&self) -> Vec<BootNode> {
+        self.resolve_all().await.boot_nodes
+    }
+
+    /// Resolve all sources like [`Self::resolve`], also telling whether any of them failed.
+    pub async fn resolve_all(&self) -> ResolvedBootNodes {
+        let mut complete = true;
+        let mut sources = self.sources.clone();
+        if let Some(path) = &self.file {
+            match read_boot_nodes_file(path) {
+                Ok(file_sources) => sources.extend(file_sources),
+                Err(e) => {
+                    log::warn!("Couldn't read boot nodes file {}: {e}", path.display());
+                    complete = false;
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let mut boot_nodes = Vec::new();
+        for source in sources {
+            let nodes = match source {
+                BootNodeSource::Node(node) => vec![node],
+                BootNodeSource::DnsAddr(addr) => {
+                    let (nodes, resolved) = self.resolve_dnsaddr(&addr).await;
+                    complete &= resolved;
+                    nodes
+                }
+            };
+            for node in nodes {
+                if seen.insert((node.peer_id, node.address.clone())) {
+                    boot_nodes.push(node);
+                }
+            }
+        }
+        ResolvedBootNodes {
+            boot_nodes,
+            complete,
+        }
+    }
+
+    /// Boot nodes listed under the `/dnsaddr` and whether all lookups succeeded.
+    async fn resolve_dnsaddr(&self, addr: &Multiaddr) -> (Vec<BootNode>, bool) {
+        let expected_peer = match addr.iter().last() {
+            Some(Protocol::P2p(peer_id)) => Some(peer_id),
+            _ => None,
+        };
+        let mut boot_nodes = Vec::new();
+        let mut queue = VecDeque::from([addr.clone()]);
+        let mut lookups = 0;
+        let mut complete = true;
+        while let Some(addr) = queue.pop_front() {
+            let Some(Protocol::Dnsaddr(name)) = addr.iter().next() else {
+                match BootNode::try_from(addr.clone()) {
+                    Ok(node) if expected_peer.is_none_or(|p| p == node.peer_id) => {
+                        boot_nodes.push(node)
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("Skipping boot node {addr}: {e}"),
+                }
+                continue;
+            };
+            if lookups == MAX_DNS_LOOKUPS {
+                log::warn!("Too many DNS lookups resolving {addr}");
+                complete = false;
+                break;
+            }
+            lookups += 1;
+            match self.lookup_dnsaddr(&name).await {
+                Ok(addrs) => queue.extend(addrs),
+                Err(e) => {
+                    log::warn!("Couldn't resolve {addr}: {e}");
+                    complete = false;
+                }
+            }
+        }
+        log::info!("Resolved {} boot node(s) from {addr}", boot_nodes.len());
+        (boot_nodes, complete)
+    }
+
+    async fn lookup_dnsaddr(&self, name: &str) -> Result<Vec<Multiaddr>, ResolveError> {
+        let Some(resolver) = &self.resolver else {
+            return Err(ResolveErrorKind::Message("No DNS resolver configured").into());
+        };
+        let lookup = resolver
+            .txt_lookup(format!("{DNSADDR_PREFIX}{name}"))
+            .await?;
+        let addrs = lookup
+            .iter()
+            .flat_map(|txt| txt.txt_data())
+            .filter_map(|data| {
+                let entry = std::str::from_utf8(data).ok()?.strip_prefix("dnsaddr=");
+                match entry.map(str::parse) {
+                    Some(Ok(addr)) => Some(addr),
+                    _ => {
+                        log::debug!("Ignoring TXT record of {name}: {data:?}");
+                        None
+                    }
+                }
+            })
+            .collect();
+        Ok(addrs)
+    }
+}
@@ -1,29 +1,43 @@
-use std::time::Duration;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 #[allow(unused_imports)]
 use futures_core::Stream;
 use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{Boxed, MemoryTransport, Transport},
+        upgrade::Version,
+    },
+    dns,
     identity::Keypair,
     multiaddr::Protocol,
-    noise,
+    noise, quic,
     swarm::{dial_opts::DialOpts, NetworkBehaviour},
-    yamux, Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder,
+    tcp, websocket, yamux, Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder,
 };
 use serde::{Deserialize, Serialize};
 
 use crate::{
     behaviour::{
         base::{BaseBehaviour, BaseConfig},
+        relay_server::RelayServerConfig,
         wrapped::Wrapped,
     },
+    boot_nodes::BootNodeResolver,
+    chain_client::ContractClient,
     cli::{BootNode, TransportArgs},
-    utils::{get_keypair, parse_env_var},
+    config::{ConfigErrors, EnvReader, NodeConfig},
+    protocol::{Network, TransportKind},
+    utils::{get_keypair, quic_to_tcp_addr, sort_by_dial_preference},
     AgentInfo, Error,
 };
 
 use super::protocol::dht_protocol;
 
+type BoxedTransport = Boxed<(PeerId, StreamMuxerBox)>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct QuicConfig {
     /// Maximum transmission unit to use during MTU discovery (default: 1452).
     pub mtu_discovery_max: u16,
@@ -33,53 +47,168 @@ pub struct QuicConfig {
     pub max_idle_timeout_ms: u32,
 }
 
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            mtu_discovery_max: 1452,
+            keep_alive_interval_ms: 5000,
+            max_idle_timeout_ms: 60000,
+        }
+    }
+}
+
 impl QuicConfig {
-    pub fn from_env() -> Self {
-        let mtu_discovery_max = parse_env_var("MTU_DISCOVERY_MAX", 1452);
-        let keep_alive_interval_ms = parse_env_var("KEEP_ALIVE_INTERVAL_MS", 5000);
-        let max_idle_timeout_ms = parse_env_var("MAX_IDLE_TIMEOUT_MS", 60000);
+    /// Override the settings given in the environment.
+    pub fn apply_env(&mut self, env: &mut EnvReader) {
+        env.var("MTU_DISCOVERY_MAX", &mut self.mtu_discovery_max);
+        env.var("KEEP_ALIVE_INTERVAL_MS", &mut self.keep_alive_interval_ms);
+        env.var("MAX_IDLE_TIMEOUT_MS", &mut self.max_idle_timeout_ms);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    /// Disable Nagle's algorithm on TCP sockets (default: true).
+    pub nodelay: bool,
+    /// Size of the listen backlog of TCP listeners (default: 1024).
+    pub listen_backlog: u32,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
         Self {
-            mtu_discovery_max,
-            keep_alive_interval_ms,
-            max_idle_timeout_ms,
+            nodelay: true,
+            listen_backlog: 1024,
         }
     }
 }
 
+impl TcpConfig {
+    /// Override the settings given in the environment.
+    pub fn apply_env(&mut self, env: &mut EnvReader) {
+        env.var("TCP_NODELAY", &mut self.nodelay);
+        env.var("TCP_LISTEN_BACKLOG", &mut self.listen_backlog);
+    }
+}
+
 pub struct P2PTransportBuilder {
     keypair: Keypair,
+    transports: Vec<TransportKind>,
     listen_addrs: Vec<Multiaddr>,
     public_addrs: Vec<Multiaddr>,
     boot_nodes: Vec<BootNode>,
+    /// Dynamic resolver with the boot nodes it has given so far
+    boot_node_resolver: Option<(BootNodeResolver, Vec<BootNode>)>,
     relay_addrs: Vec<Multiaddr>,
     relay: bool,
     quic_config: QuicConfig,
+    tcp_config: TcpConfig,
     base_config: BaseConfig,
-    // Evalutate if you need it after for eth contracts
-    // contract_client: Box<dyn ContractClient>,
+    contract_client: ContractClient,
     dht_protocol: StreamProtocol,
     agent_info: AgentInfo,
 }
 
 impl P2PTransportBuilder {
-    pub async fn from_cli(args: TransportArgs, agent_info: AgentInfo) -> anyhow::Result<Self> {
-        let listen_addrs = args.listen_addrs();
-        let keypair = get_keypair(Some(args.key)).await?;
-        // let contract_client = client::get_client(&args.rpc).await?;
-        let dht_protocol = dht_protocol(args.network);
-        Ok(Self {
+    /// Builder with the settings given in the environment.
+    pub fn new(
+        keypair: Keypair,
+        network: Network,
+        agent_info: AgentInfo,
+    ) -> Result<Self, ConfigErrors> {
+        let config = NodeConfig::from_env()?;
+        Ok(Self::with_configs(
+            keypair,
+            network,
+            agent_info,
+            config.quic,
+            config.tcp,
+            config.base,
+        ))
+    }
+
+    fn with_configs(
+        keypair: Keypair,
+        network: Network,
+        agent_info: AgentInfo,
+        quic_config: QuicConfig,
+        tcp_config: TcpConfig,
+        mut base_config: BaseConfig,
+    ) -> Self {
+        base_config.mdns |= network.mdns_enabled();
+        Self {
             keypair,
-            listen_addrs,
-            public_addrs: args.p2p_public_addrs,
-            boot_nodes: args.boot_nodes,
+            transports: vec![TransportKind::Quic],
+            listen_addrs: vec![],
+            public_addrs: vec![],
+            boot_nodes: vec![],
+            boot_node_resolver: None,
             relay_addrs: vec![],
             relay: false,
-            quic_config: QuicConfig::from_env(),
-            base_config: BaseConfig::from_env(),
-            // contract_client,
-            dht_protocol,
+            quic_config,
+            tcp_config,
+            base_config,
+            contract_client: ContractClient::default(),
+            dht_protocol: dht_protocol(network),
             agent_info,
-        })
+        }
+    }
+
+    /// Build from the node settings. The key file is generated if it doesn't exist yet.
+    pub async fn from_config(config: NodeConfig, agent_info: AgentInfo) -> anyhow::Result<Self> {
+        let NodeConfig {
+            transport,
+            quic,
+            tcp,
+            base,
+        } = config;
+        let Some(key) = transport.key else {
+            anyhow::bail!("No key file configured, set `--key`, `KEY_PATH` or `transport.key`");
+        };
+        let keypair = get_keypair(Some(key)).await?;
+        let boot_node_resolver =
+            BootNodeResolver::new(transport.boot_nodes, transport.boot_nodes_file)?;
+        let builder = Self {
+            transports: transport.transports,
+            listen_addrs: transport.listen_addrs,
+            public_addrs: transport.public_addrs,
+            ..Self::with_configs(keypair, transport.network, agent_info, quic, tcp, base)
+        };
+        Ok(builder.with_boot_node_resolver(boot_node_resolver).await)
+    }
+
+    /// Build from the config file, environment and command line, see `NodeConfig::load`.
+    /// Fails if any of the settings is invalid.
+    pub async fn from_cli(args: TransportArgs, agent_info: AgentInfo) -> anyhow::Result<Self> {
+        Self::from_config(NodeConfig::load(&args)?, agent_info).await
+    }
+
+    /// Enable the given transports in addition to the ones already enabled.
+    pub fn with_transports<I: IntoIterator<Item = TransportKind>>(mut self, kinds: I) -> Self {
+        for kind in kinds {
+            if !self.transports.contains(&kind) {
+                self.transports.push(kind);
+            }
+        }
+        self
+    }
+
+    /// Enable or disable the TCP (+ Noise + Yamux) transport.
+    pub fn with_tcp(mut self, tcp: bool) -> Self {
+        self.transports.retain(|kind| *kind != TransportKind::Tcp);
+        if tcp {
+            self.transports.push(TransportKind::Tcp);
+        }
+        self
+    }
+
+    /// Use only the in-process memory transport (with Noise and Yamux), listening on
+    /// `/memory/<n>` addresses. No sockets are bound, which makes it possible to run many
+    /// nodes in a single test deterministically.
+    pub fn with_memory_transport(mut self) -> Self {
+        self.transports = vec![TransportKind::Memory];
+        self
     }
 
     pub fn with_listen_addrs<I: IntoIterator<Item = Multiaddr>>(mut self, addrs: I) -> Self {
@@ -97,6 +226,17 @@ impl P2PTransportBuilder {
         self
     }
 
+    /// Add the boot nodes the resolver gives now. If they come from a file or DNS,
+    /// they're also resolved again periodically, connecting to new ones and dropping removed ones.
+    pub async fn with_boot_node_resolver(mut self, resolver: BootNodeResolver) -> Self {
+        let boot_nodes = resolver.resolve().await;
+        self.boot_nodes.extend(boot_nodes.iter().cloned());
+        if resolver.is_dynamic() {
+            self.boot_node_resolver = Some((resolver, boot_nodes));
+        }
+        self
+    }
+
     pub fn with_relay(mut self, relay: bool) -> Self {
         self.relay = relay;
         self
@@ -108,16 +248,46 @@ impl P2PTransportBuilder {
         self
     }
 
+    /// Listen on the relays only while the node isn't publicly reachable.
+    /// Without explicit relay addresses, boot nodes are used as relays.
+    pub fn with_auto_relay(mut self, auto_relay: bool) -> Self {
+        self.base_config.auto_relay = auto_relay;
+        self
+    }
+
+    /// Run a circuit relay v2 server. Only registered authorities may make reservations.
+    /// The relay needs public addresses to hand out to clients.
+    pub fn with_relay_server(mut self, config: RelayServerConfig) -> Self {
+        self.base_config.relay_server = Some(config);
+        self
+    }
+
     pub fn with_quic_config(mut self, f: impl FnOnce(QuicConfig) -> QuicConfig) -> Self {
         self.quic_config = f(self.quic_config);
         self
     }
 
+    pub fn with_tcp_config(mut self, f: impl FnOnce(TcpConfig) -> TcpConfig) -> Self {
+        self.tcp_config = f(self.tcp_config);
+        self
+    }
+
+    /// Persist DHT records in the given directory.
+    pub fn with_data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.base_config.data_dir = Some(dir.into());
+        self
+    }
+
     pub fn with_base_config(mut self, f: impl FnOnce(BaseConfig) -> BaseConfig) -> Self {
         self.base_config = f(self.base_config);
         self
     }
 
+    pub fn with_contract_client(mut self, contract_client: ContractClient) -> Self {
+        self.contract_client = contract_client;
+        self
+    }
+
     pub fn local_peer_id(&self) -> PeerId {
         self.keypair.public().to_peer_id()
     }
@@ -126,51 +296,198 @@ impl P2PTransportBuilder {
         self.keypair.clone()
     }
 
-    //     pub fn contract_client(&self) -> Box<dyn ContractClient> {
-    //         self.contract_client.clone_client()
-    //     }
+    pub fn contract_client(&self) -> ContractClient {
+        self.contract_client.clone()
+    }
+
+    fn quic_transport(&self) -> BoxedTransport {
+        let mut config =
+            quic::Config::new(&self.keypair).mtu_upper_bound(self.quic_config.mtu_discovery_max);
+        config.keep_alive_interval =
+            Duration::from_millis(self.quic_config.keep_alive_interval_ms as u64);
+        config.max_idle_timeout = self.quic_config.max_idle_timeout_ms;
+        quic::tokio::Transport::new(config)
+            .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn)))
+            .boxed()
+    }
+
+    fn tcp_config(&self) -> tcp::Config {
+        tcp::Config::new()
+            .nodelay(self.tcp_config.nodelay)
+            .listen_backlog(self.tcp_config.listen_backlog)
+    }
+
+    fn tcp_transport(&self) -> Result<BoxedTransport, Error> {
+        Ok(tcp::tokio::Transport::new(self.tcp_config())
+            .upgrade(Version::V1Lazy)
+            .authenticate(noise::Config::new(&self.keypair)?)
+            .multiplex(yamux::Config::default())
+            .boxed())
+    }
+
+    fn websocket_transport(&self) -> Result<BoxedTransport, Error> {
+        // DNS has to be resolved inside the WebSocket transport, so that the host name
+        // is still known when setting up TLS for `/wss` addresses.
+        let tcp = dns::tokio::Transport::system(tcp::tokio::Transport::new(self.tcp_config()))?;
+        Ok(websocket::WsConfig::new(tcp)
+            .upgrade(Version::V1Lazy)
+            .authenticate(noise::Config::new(&self.keypair)?)
+            .multiplex(yamux::Config::default())
+            .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn)))
+            .boxed())
+    }
+
+    fn memory_transport(&self) -> Result<BoxedTransport, Error> {
+        Ok(MemoryTransport::new()
+            .upgrade(Version::V1Lazy)
+            .authenticate(noise::Config::new(&self.keypair)?)
+            .multiplex(yamux::Config::default())
+            .boxed())
+    }
+
+    /// Combine all enabled transports into one.
+    fn transport(&self) -> Result<BoxedTransport, Error> {
+        if self.transports.contains(&TransportKind::Memory) {
+            return self.memory_transport();
+        }
+        let mut direct: Option<BoxedTransport> = None;
+        if self.transports.contains(&TransportKind::Quic) {
+            direct = Some(or_transport(direct, self.quic_transport()));
+        }
+        if self.transports.contains(&TransportKind::Tcp) {
+            direct = Some(or_transport(direct, self.tcp_transport()?));
+        }
+        let direct = match direct {
+            Some(transport) => Some(dns::tokio::Transport::system(transport)?.boxed()),
+            None => None,
+        };
+        // WebSocket goes first, otherwise `/dns/.../ws` addresses would be claimed
+        // by the DNS-wrapped TCP transport.
+        let transport = if self.transports.contains(&TransportKind::Ws) {
+            let ws = Some(self.websocket_transport()?);
+            match direct {
+                Some(direct) => Some(or_transport(ws, direct)),
+                None => ws,
+            }
+        } else {
+            direct
+        };
+        transport.ok_or_else(|| Error::Transport("No transport enabled".to_string()))
+    }
+
+    /// TCP listens side by side with QUIC. If no TCP listen address was given,
+    /// listen on TCP with the same IPs and ports as QUIC.
+    fn listen_addrs(&self) -> Vec<Multiaddr> {
+        let mut addrs = self.listen_addrs.clone();
+        let has_tcp = addrs
+            .iter()
+            .any(|addr| TransportKind::of(addr) == Some(TransportKind::Tcp));
+        if self.transports.contains(&TransportKind::Tcp) && !has_tcp {
+            addrs.extend(self.listen_addrs.iter().filter_map(quic_to_tcp_addr));
+        }
+        addrs
+    }
+
+    fn check_ws_addrs(&self, listen_addrs: &[Multiaddr]) {
+        let is_ws = |addr: &&Multiaddr| TransportKind::of(addr) == Some(TransportKind::Ws);
+        if !self.transports.contains(&TransportKind::Ws) {
+            return;
+        }
+        // Behind a TLS-terminating proxy the node listens on plain `/ws` and the proxy's
+        // `/wss` address is registered as public address.
+        match (
+            listen_addrs.iter().find(is_ws),
+            self.public_addrs.iter().find(is_ws),
+        ) {
+            (None, Some(public)) => {
+                log::warn!("Public WebSocket address {public} set, but not listening on WebSocket")
+            }
+            (Some(listen), None) => {
+                log::info!("Listening on WebSocket at {listen} without a public WebSocket address")
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the transport needed for the address is enabled.
+    fn check_transport(&self, addr: &Multiaddr) -> bool {
+        match TransportKind::of(addr) {
+            Some(kind) if !self.transports.contains(&kind) => {
+                log::warn!("Address {addr} requires the {kind:?} transport, which is not enabled");
+                false
+            }
+            _ => true,
+        }
+    }
 
-    fn build_swarm<T: NetworkBehaviour>(
+    /// Build a swarm with a custom behaviour composed around `BaseBehaviour`.
+    pub fn build_swarm<T: NetworkBehaviour>(
         mut self,
         behaviour: impl FnOnce(BaseBehaviour) -> T,
     ) -> Result<Swarm<T>, Error> {
+        let transport = self.transport()?;
+        // QUIC addresses are given by default, with other transports only their TCP
+        // counterparts can be used
+        let listen_addrs: Vec<_> = self
+            .listen_addrs()
+            .into_iter()
+            .filter(|addr| self.check_transport(addr))
+            .collect();
+        if listen_addrs.is_empty() && !self.listen_addrs.is_empty() {
+            return Err(Error::Transport(format!(
+                "None of the listen addresses {:?} can be used with the enabled transports {:?}",
+                self.listen_addrs, self.transports
+            )));
+        }
+        self.check_ws_addrs(&listen_addrs);
+        if self.base_config.relay_server.is_some() && self.public_addrs.is_empty() {
+            log::warn!("Relay server enabled without public addresses, reservations will fail");
+        }
+        for addr in self.boot_nodes.iter().map(|bn| &bn.address) {
+            self.check_transport(addr);
+        }
+        for addr in &self.relay_addrs {
+            self.check_transport(addr);
+        }
+
+        // If relay node not specified explicitly, use boot nodes
+        let auto_relay = self.base_config.auto_relay;
+        if (self.relay || auto_relay) && self.relay_addrs.is_empty() {
+            self.relay_addrs = self
+                .boot_nodes
+                .iter()
+                .map(|bn| bn.address.clone().with(Protocol::P2p(bn.peer_id)))
+                .collect();
+        }
+
         let mut swarm = SwarmBuilder::with_existing_identity(self.keypair)
             .with_tokio()
-            .with_quic_config(|config| {
-                let mut config = config.mtu_upper_bound(self.quic_config.mtu_discovery_max);
-                config.keep_alive_interval =
-                    Duration::from_millis(self.quic_config.keep_alive_interval_ms as u64);
-                config.max_idle_timeout = self.quic_config.max_idle_timeout_ms;
-                config
-            })
-            .with_dns()?
+            .with_other_transport(|_| transport)
+            .expect("infallible")
             .with_relay_client(noise::Config::new, yamux::Config::default)?
             .with_behaviour(|keypair: &Keypair, relay| {
-                let base = BaseBehaviour::new(
+                let mut base = BaseBehaviour::new(
                     keypair,
-                    //   self.contract_client,
+                    self.contract_client,
                     self.base_config,
                     self.boot_nodes.clone(),
                     relay,
                     self.dht_protocol,
                     self.agent_info,
-                );
-                behaviour(base)
+                )?;
+                if let Some((resolver, resolved)) = self.boot_node_resolver {
+                    base.set_boot_node_resolver(resolver, resolved);
+                }
+                if self.relay || auto_relay {
+                    base.set_relays(self.relay_addrs.clone());
+                }
+                Ok(behaviour(base))
             })
-            .expect("infallible")
+            .map_err(|e| Error::Behaviour(e.to_string()))?
             .build();
 
-        // If relay node not specified explicitly, use boot nodes
-        if self.relay && self.relay_addrs.is_empty() {
-            self.relay_addrs = self
-                .boot_nodes
-                .iter()
-                .map(|bn| bn.address.clone().with(Protocol::P2p(bn.peer_id)))
-                .collect();
-        }
-
         // Listen on provided addresses
-        for addr in self.listen_addrs {
+        for addr in listen_addrs {
             swarm.listen_on(addr)?;
         }
 
@@ -179,18 +496,16 @@ impl P2PTransportBuilder {
             swarm.add_external_address(addr);
         }
 
-        // Connect to boot nodes
+        // Connect to boot nodes. A boot node may be listed once per transport,
+        // in which case all of its addresses are dialed in order of preference.
+        let mut boot_node_addrs: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
         for BootNode { peer_id, address } in self.boot_nodes {
-            log::info!("Connecting to boot node {peer_id} at {address}");
-            swarm.dial(DialOpts::peer_id(peer_id).addresses(vec![address]).build())?;
+            boot_node_addrs.entry(peer_id).or_default().push(address);
         }
-
-        // Connect to relay and listen for relayed connections
-        if self.relay {
-            for addr in self.relay_addrs {
-                log::info!("Connecting to relay {addr}");
-                swarm.listen_on(addr.with(Protocol::P2pCircuit))?;
-            }
+        for (peer_id, mut addrs) in boot_node_addrs {
+            sort_by_dial_preference(&mut addrs);
+            log::info!("Connecting to boot node {peer_id} at {addrs:?}");
+            swarm.dial(DialOpts::peer_id(peer_id).addresses(addrs).build())?;
         }
 
         Ok(swarm)
@@ -202,3 +517,13 @@ impl P2PTransportBuilder {
         Ok(swarm)
     }
 }
+
+fn or_transport(first: Option<BoxedTransport>, second: BoxedTransport) -> BoxedTransport {
+    match first {
+        Some(first) => first
+            .or_transport(second)
+            .map(|either, _| either.into_inner())
+            .boxed(),
+        None => second,
+    }
+}
@@ -1,7 +1,11 @@
-use futures::future::ready;
+use futures::{future::ready, stream};
 use libp2p::{futures::Stream, PeerId};
 use std::{collections::HashSet, pin::Pin, str::FromStr, time::Duration};
-use tokio_stream::{wrappers::IntervalStream, StreamExt};
+use tokio::sync::watch;
+use tokio_stream::{
+    wrappers::{IntervalStream, WatchStream},
+    StreamExt,
+};
 
 // A dummy implementation of a chain client, just to provide the authority list.
 
@@ -12,20 +16,33 @@ pub type AuthorityPeers = HashSet<PeerId>;
 pub type NodeStream =
     Pin<Box<dyn Stream<Item = Result<AuthorityPeers, ClientError>> + Send + 'static>>;
 
-pub struct ContractClient;
-
-impl Default for ContractClient {
-    fn default() -> Self {
-        Self
-    }
+#[derive(Clone, Default)]
+pub struct ContractClient {
+    authorities: Option<watch::Receiver<AuthorityPeers>>,
 }
 
 impl ContractClient {
+    /// Client reporting the authority set from the given channel, e.g. for tests.
+    /// The current set is reported immediately and every change right away.
+    pub fn with_authorities(authorities: watch::Receiver<AuthorityPeers>) -> Self {
+        Self {
+            authorities: Some(authorities),
+        }
+    }
+
     pub fn network_nodes_stream(&self, interval: Duration) -> NodeStream {
-        Box::pin(
-            IntervalStream::new(tokio::time::interval(interval))
-                .then(move |_| ready(Ok(get_authority_peers()))),
-        )
+        match &self.authorities {
+            Some(authorities) => Box::pin(
+                WatchStream::new(authorities.clone())
+                    .map(Ok)
+                    // The stream is expected to be infinite
+                    .chain(stream::pending()),
+            ),
+            None => Box::pin(
+                IntervalStream::new(tokio::time::interval(interval))
+                    .then(move |_| ready(Ok(get_authority_peers()))),
+            ),
+        }
     }
 }
 
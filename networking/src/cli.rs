@@ -1,13 +1,32 @@
 use clap::Args;
-use libp2p::{Multiaddr, PeerId};
-use std::{path::PathBuf, str::FromStr};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use std::{
+    fmt::{Display, Formatter},
+    path::PathBuf,
+    str::FromStr,
+};
 
-use crate::protocol::Network;
+use crate::{
+    boot_nodes::BootNodeSource,
+    protocol::{Network, TransportKind},
+};
 
+/// Command line arguments of a node. Each one overrides the corresponding setting
+/// of the config file, see `NodeConfig`.
 #[derive(Args, Clone)]
 pub struct TransportArgs {
+    #[arg(
+        long,
+        env = "CONFIG_FILE",
+        help = "TOML file with node settings. Environment variables and arguments take precedence"
+    )]
+    pub config: Option<PathBuf>,
+
+    #[arg(long, help = "Print the effective configuration as TOML and exit")]
+    pub print_config: bool,
+
     #[arg(short, long, env = "KEY_PATH", help = "Path to libp2p key file")]
-    pub key: PathBuf,
+    pub key: Option<PathBuf>,
 
     #[arg(
         long,
@@ -20,7 +39,8 @@ pub struct TransportArgs {
     #[arg(
         long,
         env,
-        help = "Public address(es) on which the p2p node can be reached",
+        help = "Public address(es) on which the p2p node can be reached. \
+            Behind a TLS-terminating proxy, use the proxy's `/wss` address",
         value_delimiter = ','
     )]
     pub p2p_public_addrs: Vec<Multiaddr>,
@@ -28,16 +48,47 @@ pub struct TransportArgs {
     #[arg(
         long,
         env,
-        help = "Connect to boot node '<peer_id> <address>'.",
+        help = "Connect to boot node '<peer_id> <address>', '<address>/p2p/<peer_id>' \
+            or all boot nodes listed under '/dnsaddr/<domain>'.",
         value_delimiter = ',',
         num_args = 1..,
     )]
-    pub boot_nodes: Vec<BootNode>,
+    pub boot_nodes: Vec<BootNodeSource>,
+
+    #[arg(
+        long,
+        env,
+        help = "File listing boot nodes, one per line in any of the `--boot-nodes` formats"
+    )]
+    pub boot_nodes_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "P2P_TRANSPORTS",
+        help = "Transports to enable (quic, tcp, ws). QUIC is preferred when dialing, \
+            then TCP, then WebSocket [default: quic]",
+        value_delimiter = ','
+    )]
+    pub transports: Vec<TransportKind>,
+
+    #[arg(
+        long,
+        env,
+        help = "Run a circuit relay server, accepting reservations from authorities only"
+    )]
+    pub relay_server: bool,
+
+    #[arg(
+        long,
+        env,
+        help = "Directory for persistent node data, e.g. DHT records"
+    )]
+    pub data_dir: Option<PathBuf>,
     //     #[command(flatten)]
     //     pub rpc: RpcArgs,
-    /// Network to connect to (mainnet or testnet)
-    #[arg(long, env, default_value = "mainnet")]
-    pub network: Network,
+    /// Network to connect to (mainnet, testnet or local) [default: mainnet]
+    #[arg(long, env)]
+    pub network: Option<Network>,
 }
 
 impl TransportArgs {
@@ -57,11 +108,12 @@ impl FromStr for BootNode {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut parts = s.split_whitespace();
-        let peer_id = parts
-            .next()
-            .ok_or("Boot node peer ID missing")?
-            .parse()
-            .map_err(|_| "Invalid peer ID")?;
+        let first = parts.next().ok_or("Boot node peer ID missing")?;
+        if first.starts_with('/') {
+            let address: Multiaddr = first.parse().map_err(|_| "Invalid address")?;
+            return address.try_into();
+        }
+        let peer_id = first.parse().map_err(|_| "Invalid peer ID")?;
         let address = parts
             .next()
             .ok_or("Boot node address missing")?
@@ -70,3 +122,28 @@ impl FromStr for BootNode {
         Ok(Self { peer_id, address })
     }
 }
+
+impl Display for BootNode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.address
+                .clone()
+                .with_p2p(self.peer_id)
+                .unwrap_or_else(|a| a)
+        )
+    }
+}
+
+impl TryFrom<Multiaddr> for BootNode {
+    type Error = &'static str;
+
+    /// Split an address ending in `/p2p/<peer_id>`.
+    fn try_from(mut address: Multiaddr) -> Result<Self, Self::Error> {
+        match address.pop() {
+            Some(Protocol::P2p(peer_id)) => Ok(Self { peer_id, address }),
+            _ => Err("Boot node address must end with /p2p/<peer_id>"),
+        }
+    }
+}
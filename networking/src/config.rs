@@ -0,0 +1,602 @@
+use std::{
+    fmt::{Display, Formatter},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::{
+    behaviour::{base::BaseConfig, relay_server::RelayServerConfig},
+    boot_nodes::BootNodeSource,
+    builder::{QuicConfig, TcpConfig},
+    cli::TransportArgs,
+    protocol::{Network, TransportKind},
+};
+
+/// Longest accepted lifetime of cached addresses, so that expiry times can't overflow.
+const MAX_TTL: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// Where an invalid setting came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    File(PathBuf),
+    Env,
+    /// Settings which contradict each other, checked after merging all sources
+    Merged,
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "config file {}", path.display()),
+            Self::Env => write!(f, "environment"),
+            Self::Merged => write!(f, "merged config"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Key in the config file like `base.probe_timeout`, or name of the environment variable.
+    /// `config` if the whole file is unusable.
+    pub key: String,
+    pub source: ConfigSource,
+    pub message: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} ({})", self.key, self.message, self.source)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Every problem found while loading the configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl Display for ConfigErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid configuration")?;
+        for err in &self.0 {
+            write!(f, "\n  {err}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// Reads settings from environment variables. Variables which aren't set leave the
+/// setting as it is, invalid ones are collected as errors.
+#[derive(Default)]
+pub struct EnvReader {
+    errors: Vec<ConfigError>,
+}
+
+impl EnvReader {
+    /// `None` if the variable isn't set or invalid.
+    pub fn parse<T>(&mut self, var: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self.get(var)?;
+        value
+            .trim()
+            .parse()
+            .map_err(|e| self.error(var, format!("invalid value `{value}`: {e}")))
+            .ok()
+    }
+
+    pub fn var<T>(&mut self, var: &str, setting: &mut T)
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        if let Some(value) = self.parse(var) {
+            *setting = value;
+        }
+    }
+
+    pub fn secs(&mut self, var: &str, setting: &mut Duration) {
+        if let Some(secs) = self.parse(var) {
+            *setting = Duration::from_secs(secs);
+        }
+    }
+
+    pub fn millis(&mut self, var: &str, setting: &mut Duration) {
+        if let Some(millis) = self.parse(var) {
+            *setting = Duration::from_millis(millis);
+        }
+    }
+
+    /// Comma-separated list. Invalid entries are reported and left out.
+    pub fn list<T, E: Display>(
+        &mut self,
+        var: &str,
+        setting: &mut Vec<T>,
+        parse: impl Fn(&str) -> Result<T, E>,
+    ) {
+        let Some(value) = self.get(var) else {
+            return;
+        };
+        *setting = value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                parse(entry)
+                    .map_err(|e| self.error(var, format!("invalid entry `{entry}`: {e}")))
+                    .ok()
+            })
+            .collect();
+    }
+
+    pub fn into_errors(self) -> Vec<ConfigError> {
+        self.errors
+    }
+
+    fn get(&mut self, var: &str) -> Option<String> {
+        match std::env::var(var) {
+            Ok(value) => Some(value),
+            Err(std::env::VarError::NotPresent) => None,
+            Err(std::env::VarError::NotUnicode(_)) => {
+                self.error(var, "value is not valid unicode".to_string());
+                None
+            }
+        }
+    }
+
+    fn error(&mut self, var: &str, message: String) {
+        self.errors.push(ConfigError {
+            key: var.to_string(),
+            source: ConfigSource::Env,
+            message,
+        });
+    }
+}
+
+/// Settings of the `[transport]` section, which correspond to `TransportArgs`.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportConfig {
+    /// Path to the libp2p key file, which is generated if it doesn't exist.
+    pub key: Option<PathBuf>,
+    /// Addresses on which the node listens.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Addresses on which the node can be reached.
+    pub public_addrs: Vec<Multiaddr>,
+    /// Boot nodes in any of the `--boot-nodes` formats.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub boot_nodes: Vec<BootNodeSource>,
+    /// File listing boot nodes, one per line.
+    pub boot_nodes_file: Option<PathBuf>,
+    /// Transports to enable (default: quic).
+    pub transports: Vec<TransportKind>,
+    /// Network to connect to (default: mainnet).
+    pub network: Network,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            key: None,
+            listen_addrs: vec![],
+            public_addrs: vec![],
+            boot_nodes: vec![],
+            boot_nodes_file: None,
+            transports: vec![TransportKind::Quic],
+            network: Network::default(),
+        }
+    }
+}
+
+/// All settings of a node. They're layered with increasing precedence: defaults,
+/// config file, environment variables and command line arguments.
+///
+/// The config file is TOML with a section per struct, for example
+///
+/// ```toml
+/// [transport]
+/// key = "/data/node.key"
+/// listen_addrs = ["/ip4/0.0.0.0/udp/12345/quic-v1"]
+/// transports = ["quic", "tcp"]
+///
+/// [quic]
+/// max_idle_timeout_ms = 30000
+///
+/// [base]
+/// probe_timeout = 10
+/// msg_interval = 100
+///
+/// [base.address_policy]
+/// deny = ["10.0.0.0/8"]
+/// ```
+///
+/// Every section and key is optional, unknown keys are rejected. Durations are given in
+/// seconds, except for `msg_interval` and `reconnect_backoff_min`, which are in
+/// milliseconds like their environment variables. The pubsub (`msg_interval`,
+/// `max_pubsub_msg_size`) and whitelist (`onchain_update_interval`) settings are
+/// part of `[base]`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub transport: TransportConfig,
+    pub quic: QuicConfig,
+    pub tcp: TcpConfig,
+    pub base: BaseConfig,
+}
+
+impl NodeConfig {
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let error = |key: String, message: String| ConfigError {
+            key,
+            source: ConfigSource::File(path.to_path_buf()),
+            message,
+        };
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| error("config".to_string(), format!("cannot read file: {e}")))?;
+        serde_path_to_error::deserialize(toml::Deserializer::new(&content)).map_err(|e| {
+            let key = match e.path().iter().next() {
+                Some(_) => e.path().to_string(),
+                None => "config".to_string(),
+            };
+            let e = e.into_inner();
+            let message = match e.span() {
+                Some(span) => {
+                    let line = content[..span.start].lines().count().max(1);
+                    format!("{} (line {line})", e.message())
+                }
+                None => e.message().to_string(),
+            };
+            error(key, message)
+        })
+    }
+
+    /// Read the config file named in `args`, if any, and override it with the
+    /// environment and `args`. Fails with every invalid or contradicting setting.
+    pub fn load(args: &TransportArgs) -> Result<Self, ConfigErrors> {
+        let mut errors = vec![];
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path).unwrap_or_else(|e| {
+                errors.push(e);
+                Self::default()
+            }),
+            None => Self::default(),
+        };
+        let mut env = EnvReader::default();
+        config.apply_env(&mut env);
+        errors.extend(env.into_errors());
+        config.apply_args(args);
+        config.checked(errors)
+    }
+
+    /// Defaults overridden with the environment, for nodes without command line arguments.
+    /// Fails with every invalid or contradicting setting.
+    pub fn from_env() -> Result<Self, ConfigErrors> {
+        let mut config = Self::default();
+        let mut env = EnvReader::default();
+        config.apply_env(&mut env);
+        config.checked(env.into_errors())
+    }
+
+    fn checked(self, mut errors: Vec<ConfigError>) -> Result<Self, ConfigErrors> {
+        errors.extend(self.validate());
+        match errors.is_empty() {
+            true => Ok(self),
+            false => Err(ConfigErrors(errors)),
+        }
+    }
+
+    /// Override the settings given in the environment. The environment variables
+    /// of the transport settings are read by clap, as part of `TransportArgs`.
+    pub fn apply_env(&mut self, env: &mut EnvReader) {
+        self.quic.apply_env(env);
+        self.tcp.apply_env(env);
+        self.base.apply_env(env);
+    }
+
+    /// Override the settings given on the command line.
+    pub fn apply_args(&mut self, args: &TransportArgs) {
+        let transport = &mut self.transport;
+        if args.key.is_some() {
+            transport.key.clone_from(&args.key);
+        }
+        if !args.p2p_listen_addrs.is_empty() {
+            transport.listen_addrs.clone_from(&args.p2p_listen_addrs);
+        }
+        if !args.p2p_public_addrs.is_empty() {
+            transport.public_addrs.clone_from(&args.p2p_public_addrs);
+        }
+        if !args.boot_nodes.is_empty() {
+            transport.boot_nodes.clone_from(&args.boot_nodes);
+        }
+        if args.boot_nodes_file.is_some() {
+            transport.boot_nodes_file.clone_from(&args.boot_nodes_file);
+        }
+        if !args.transports.is_empty() {
+            transport.transports.clone_from(&args.transports);
+        }
+        if let Some(network) = args.network {
+            transport.network = network;
+        }
+        if args.relay_server && self.base.relay_server.is_none() {
+            // Invalid values have already been reported by `apply_env`
+            let mut relay_server = RelayServerConfig::default();
+            relay_server.apply_env(&mut EnvReader::default());
+            self.base.relay_server = Some(relay_server);
+        }
+        if args.data_dir.is_some() {
+            self.base.data_dir.clone_from(&args.data_dir);
+        }
+    }
+
+    /// Check for settings which can't work, alone or together with others.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = vec![];
+        let mut error = |key: &str, message: String| {
+            errors.push(ConfigError {
+                key: key.to_string(),
+                source: ConfigSource::Merged,
+                message,
+            })
+        };
+        let base = &self.base;
+
+        if self.transport.transports.is_empty() {
+            error(
+                "transport.transports",
+                "at least one transport is needed".to_string(),
+            );
+        }
+        if self.quic.keep_alive_interval_ms >= self.quic.max_idle_timeout_ms {
+            error(
+                "quic.keep_alive_interval_ms",
+                format!(
+                    "{} ms is not shorter than max_idle_timeout_ms ({} ms), idle connections would be closed",
+                    self.quic.keep_alive_interval_ms, self.quic.max_idle_timeout_ms
+                ),
+            );
+        }
+        let intervals = [
+            ("base.onchain_update_interval", base.onchain_update_interval),
+            ("base.identify_interval", base.identify_interval),
+            ("base.kad_bootstrap_interval", base.kad_bootstrap_interval),
+            (
+                "base.addr_cache_save_interval",
+                base.addr_cache_save_interval,
+            ),
+            (
+                "base.boot_node_refresh_interval",
+                base.boot_node_refresh_interval,
+            ),
+            ("base.ping_interval", base.ping_interval),
+            ("base.relay_retry_interval", base.relay_retry_interval),
+        ];
+        for (key, interval) in intervals {
+            if interval.is_zero() {
+                error(key, "must not be zero".to_string());
+            }
+        }
+        let ttls = [
+            ("base.addr_cache_ttl", base.addr_cache_ttl),
+            ("base.external_addr_ttl", base.external_addr_ttl),
+        ];
+        for (key, ttl) in ttls {
+            if ttl > MAX_TTL {
+                error(
+                    key,
+                    format!("{ttl:?} is longer than the maximum of {MAX_TTL:?}"),
+                );
+            }
+        }
+        if base.probe_timeout.is_zero() {
+            error("base.probe_timeout", "must not be zero".to_string());
+        }
+        if base.autonat_timeout < base.probe_timeout {
+            error(
+                "base.autonat_timeout",
+                format!(
+                    "{:?} is shorter than probe_timeout ({:?})",
+                    base.autonat_timeout, base.probe_timeout
+                ),
+            );
+        }
+        if base.max_concurrent_probes == 0 {
+            error(
+                "base.max_concurrent_probes",
+                "must not be zero, no probe could ever run".to_string(),
+            );
+        }
+        if base.max_queued_probes == 0 {
+            error(
+                "base.max_queued_probes",
+                "must not be zero, probes would fail while all slots are busy".to_string(),
+            );
+        }
+        if base.reconnect_backoff_min > base.reconnect_backoff_max {
+            error(
+                "base.reconnect_backoff_min",
+                format!(
+                    "{:?} is longer than reconnect_backoff_max ({:?})",
+                    base.reconnect_backoff_min, base.reconnect_backoff_max
+                ),
+            );
+        }
+        if base.auto_relay && base.relay_reservations == 0 {
+            error(
+                "base.relay_reservations",
+                "must not be zero with auto_relay enabled".to_string(),
+            );
+        }
+        errors
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        transport: TransportArgs,
+    }
+
+    const CONFIG: &str = r#"
+        [transport]
+        key = "node.key"
+        listen_addrs = ["/ip4/0.0.0.0/udp/12345/quic-v1"]
+        boot_nodes = ["/dnsaddr/bootstrap.example.org"]
+        transports = ["quic", "tcp"]
+        network = "testnet"
+
+        [quic]
+        max_idle_timeout_ms = 30000
+
+        [base]
+        probe_timeout = 10
+        msg_interval = 100
+        reachability_interval = 600
+
+        [base.address_policy]
+        deny = ["10.0.0.0/8"]
+    "#;
+
+    #[test]
+    fn test_parse_and_print() {
+        let config: NodeConfig = toml::from_str(CONFIG).unwrap();
+        assert_eq!(
+            config.transport.transports,
+            [TransportKind::Quic, TransportKind::Tcp]
+        );
+        assert_eq!(config.transport.network, Network::Testnet);
+        assert_eq!(config.quic.max_idle_timeout_ms, 30000);
+        assert_eq!(config.quic.mtu_discovery_max, 1452);
+        assert_eq!(config.base.probe_timeout, Duration::from_secs(10));
+        assert_eq!(config.base.msg_interval, Duration::from_millis(100));
+        assert_eq!(
+            config.base.reachability_interval,
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(
+            config.base.address_policy.deny,
+            ["10.0.0.0/8".parse().unwrap()]
+        );
+        assert!(config.base.address_policy.allow_dns);
+
+        let printed: NodeConfig = toml::from_str(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(printed.to_toml().unwrap(), config.to_toml().unwrap());
+        assert_eq!(
+            printed.transport.boot_nodes[0].to_string(),
+            "/dnsaddr/bootstrap.example.org"
+        );
+
+        let unknown = toml::from_str::<NodeConfig>("[base]\nprobe_timeot = 10");
+        assert!(unknown.unwrap_err().to_string().contains("probe_timeot"));
+    }
+
+    #[test]
+    fn test_args_override_file() {
+        let mut config: NodeConfig = toml::from_str(CONFIG).unwrap();
+        let cli = Cli::try_parse_from([
+            "node",
+            "--key",
+            "other.key",
+            "--transports",
+            "ws",
+            "--relay-server",
+        ])
+        .unwrap();
+        config.apply_args(&cli.transport);
+        assert_eq!(config.transport.key, Some("other.key".into()));
+        assert_eq!(config.transport.transports, [TransportKind::Ws]);
+        assert_eq!(config.transport.network, Network::Testnet);
+        assert_eq!(config.transport.listen_addrs.len(), 1);
+        assert!(config.base.relay_server.is_some());
+    }
+
+    #[test]
+    fn test_memory_transport_not_configurable() {
+        let config = "[transport]\ntransports = [\"memory\"]\n";
+        assert!(toml::from_str::<NodeConfig>(config).is_err());
+        assert!(<TransportKind as clap::ValueEnum>::from_str("memory", true).is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[base]\nping_interval = 10\nprobe_timeout = \"abc\"\n",
+        )
+        .unwrap();
+        let err = NodeConfig::from_file(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.key, "base.probe_timeout");
+        assert_eq!(err.source, ConfigSource::File(path));
+        assert!(err.message.ends_with("(line 3)"), "{}", err.message);
+
+        std::env::set_var("CONFIG_TEST_SECS", "abc");
+        std::env::set_var("CONFIG_TEST_LIST", "quic, udp,tcp");
+        let mut env = EnvReader::default();
+        let mut secs = Duration::from_secs(1);
+        env.secs("CONFIG_TEST_SECS", &mut secs);
+        env.secs("CONFIG_TEST_UNSET", &mut secs);
+        let mut transports = vec![];
+        env.list("CONFIG_TEST_LIST", &mut transports, |s| {
+            <TransportKind as clap::ValueEnum>::from_str(s, true)
+        });
+        assert_eq!(secs, Duration::from_secs(1));
+        assert_eq!(transports, [TransportKind::Quic, TransportKind::Tcp]);
+        let keys: Vec<_> = env
+            .into_errors()
+            .into_iter()
+            .map(|err| (err.key, err.source))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                ("CONFIG_TEST_SECS".to_string(), ConfigSource::Env),
+                ("CONFIG_TEST_LIST".to_string(), ConfigSource::Env)
+            ]
+        );
+
+        let mut config = NodeConfig::default();
+        assert!(config.validate().is_empty());
+        // Disables the monitor, like `REACHABILITY_INTERVAL_SEC=0`
+        config.base.reachability_interval = Some(Duration::ZERO);
+        assert!(config.validate().is_empty());
+        config.base.autonat_timeout = Duration::from_secs(10);
+        config.base.ping_interval = Duration::ZERO;
+        config.base.addr_cache_ttl = Duration::from_secs(99999999999);
+        config.base.max_queued_probes = 0;
+        config.transport.transports.clear();
+        let keys: Vec<_> = config.validate().into_iter().map(|err| err.key).collect();
+        assert_eq!(
+            keys,
+            [
+                "transport.transports",
+                "base.ping_interval",
+                "base.addr_cache_ttl",
+                "base.autonat_timeout",
+                "base.max_queued_probes"
+            ]
+        );
+    }
+}
@@ -4,9 +4,11 @@ use libp2p::{noise, swarm::DialError, TransportError};
 use serde::{Deserialize, Serialize};
 
 pub mod behaviour;
+pub mod boot_nodes;
 pub mod builder;
 pub mod chain_client;
 pub mod cli;
+pub mod config;
 pub mod protocol;
 pub mod utils;
 
@@ -20,6 +22,10 @@ pub enum Error {
     Dial(#[from] DialError),
     #[error("Decoding message failed: {0}")]
     Decode(String),
+    #[error("Opening DHT store failed: {0}")]
+    Store(String),
+    #[error("{0}")]
+    Behaviour(String),
     // #[error("{0}")]
     // Contract(#[from] sqd_contract_client::ClientError),
 }
@@ -1,5 +1,6 @@
 use clap::ValueEnum;
-use libp2p::StreamProtocol;
+use libp2p::{multiaddr::Protocol, Multiaddr, StreamProtocol};
+use serde::{Deserialize, Serialize};
 
 pub const BLOCKS_TOPIC: &str = "/iceberg/blocks/1.0.0";
 
@@ -7,19 +8,68 @@ pub const ID_PROTOCOL: &str = "/iceberg/1.0.0";
 
 pub const MAX_PUBSUB_MSG_SIZE: usize = 65536;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default, Serialize, Deserialize)]
 #[clap(rename_all = "kebab_case")]
+#[serde(rename_all = "kebab-case")]
 pub enum Network {
+    /// Local development cluster, peers are discovered with mDNS
+    Local,
     Testnet,
     #[default]
     Mainnet,
 }
 
-pub const KNOWN_TOPICS: [&'static str; 1] = [BLOCKS_TOPIC];
+impl Network {
+    /// Whether peers should be discovered on the local network.
+    pub const fn mdns_enabled(self) -> bool {
+        matches!(self, Network::Local)
+    }
+}
+
+pub const KNOWN_TOPICS: [&str; 1] = [BLOCKS_TOPIC];
 
 pub const fn dht_protocol(network: Network) -> StreamProtocol {
     match network {
+        Network::Local => StreamProtocol::new("/iceberg/dht/local/1.0.0"),
         Network::Testnet => StreamProtocol::new("/iceberg/dht/testnet/1.0.0"),
         Network::Mainnet => StreamProtocol::new("/iceberg/dht/mainnet/1.0.0"),
     }
 }
+
+/// Transports a node can use. The declaration order is the dial preference order.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum, Serialize, Deserialize,
+)]
+#[clap(rename_all = "kebab_case")]
+#[serde(rename_all = "kebab-case")]
+pub enum TransportKind {
+    Quic,
+    Tcp,
+    /// WebSocket over TCP. `/wss` addresses are dialed with TLS, which allows
+    /// reaching nodes behind a TLS-terminating reverse proxy.
+    Ws,
+    /// In-process transport for tests, only enabled by
+    /// `P2PTransportBuilder::with_memory_transport`.
+    #[value(skip)]
+    #[serde(skip)]
+    Memory,
+}
+
+impl TransportKind {
+    /// Transport needed to dial the given address, `None` if it cannot be determined.
+    pub fn of(addr: &Multiaddr) -> Option<Self> {
+        let mut kind = None;
+        for protocol in addr.iter() {
+            match protocol {
+                Protocol::QuicV1 => kind = Some(Self::Quic),
+                Protocol::Tcp(_) => kind = Some(Self::Tcp),
+                Protocol::Ws(_) | Protocol::Wss(_) => kind = Some(Self::Ws),
+                Protocol::Memory(_) => kind = Some(Self::Memory),
+                // The relayed part of the address is dialed over the relay connection
+                Protocol::P2pCircuit => break,
+                _ => {}
+            }
+        }
+        kind
+    }
+}
@@ -1,10 +1,13 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{path::PathBuf, task::Context};
 
 use libp2p::{
     identity::{ed25519, Keypair},
     multiaddr::Protocol,
     Multiaddr,
 };
+use tokio::time::Interval;
+
+use crate::protocol::TransportKind;
 
 /// Load key from file or generate and save to file.
 pub async fn get_keypair(path: Option<PathBuf>) -> anyhow::Result<Keypair> {
@@ -30,86 +33,57 @@ pub async fn get_keypair(path: Option<PathBuf>) -> anyhow::Result<Keypair> {
     }
 }
 
-pub fn parse_env_var<T: FromStr>(var: &str, default: T) -> T {
-    std::env::var(var)
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(default)
+/// Whether the interval ticked. Polls until the interval is pending, because a ready
+/// `poll_tick` doesn't register the waker for the next tick.
+pub fn poll_ticks(interval: &mut Interval, cx: &mut Context<'_>) -> bool {
+    let mut ticked = false;
+    while interval.poll_tick(cx).is_ready() {
+        ticked = true;
+    }
+    ticked
 }
 
-pub fn addr_is_reachable(addr: &Multiaddr) -> bool {
-    match addr.iter().next() {
-        Some(Protocol::Ip4(addr)) => {
-            !(addr.is_loopback() || addr.is_link_local())
-                // We need to allow private addresses for testing in local environment
-                && (!addr.is_private() || std::env::var("PRIVATE_NETWORK").is_ok())
-        }
-        Some(Protocol::Ip6(addr)) => !addr.is_loopback(),
-        Some(Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) | Protocol::Dnsaddr(_)) => {
-            true
-        }
-        _ => false,
-    }
+/// Order addresses by dial preference: direct before relayed, then QUIC, TCP and WebSocket.
+pub fn sort_by_dial_preference(addrs: &mut [Multiaddr]) {
+    addrs.sort_by_key(|addr| {
+        let relayed = addr.iter().any(|p| p == Protocol::P2pCircuit);
+        (
+            relayed,
+            TransportKind::of(addr).map_or(u8::MAX, |kind| kind as u8),
+        )
+    });
+}
+
+/// Map a QUIC listen address onto the TCP address with the same IP and port.
+pub fn quic_to_tcp_addr(addr: &Multiaddr) -> Option<Multiaddr> {
+    let mut iter = addr.iter();
+    let ip = iter
+        .next()
+        .filter(|p| matches!(p, Protocol::Ip4(_) | Protocol::Ip6(_)))?;
+    let Some(Protocol::Udp(port)) = iter.next() else {
+        return None;
+    };
+    let Some(Protocol::QuicV1) = iter.next() else {
+        return None;
+    };
+    Some(Multiaddr::empty().with(ip).with(Protocol::Tcp(port)))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use libp2p::multiaddr::multiaddr;
 
     #[test]
-    fn test_addr_is_reachable() {
-        assert!(!addr_is_reachable(&multiaddr!(
-            Ip4([127, 0, 0, 1]),
-            Tcp(12345u16)
-        )));
-        assert!(!addr_is_reachable(&multiaddr!(
-            Ip4([169, 254, 0, 1]),
-            Tcp(12345u16)
-        )));
-        assert!(!addr_is_reachable(&multiaddr!(
-            Ip4([192, 168, 0, 1]),
-            Tcp(12345u16)
-        )));
-        assert!(!addr_is_reachable(&multiaddr!(
-            Ip4([10, 0, 0, 1]),
-            Tcp(12345u16)
-        )));
-        assert!(!addr_is_reachable(&multiaddr!(
-            Ip4([172, 16, 0, 1]),
-            Tcp(12345u16)
-        )));
-        assert!(!addr_is_reachable(&multiaddr!(
-            Ip6([0, 0, 0, 0, 0, 0, 0, 1]),
-            Tcp(12345u16)
-        )));
-
-        std::env::set_var("PRIVATE_NETWORK", "1");
-
-        assert!(!addr_is_reachable(&multiaddr!(
-            Ip4([127, 0, 0, 1]),
-            Tcp(12345u16)
-        )));
-        assert!(!addr_is_reachable(&multiaddr!(
-            Ip4([169, 254, 0, 1]),
-            Tcp(12345u16)
-        )));
-        assert!(!addr_is_reachable(&multiaddr!(
-            Ip6([0, 0, 0, 0, 0, 0, 0, 1]),
-            Tcp(12345u16)
-        )));
+    fn test_dial_preference() {
+        let quic: Multiaddr = "/ip4/1.2.3.4/udp/9000/quic-v1".parse().unwrap();
+        let tcp: Multiaddr = "/ip4/1.2.3.4/tcp/9000".parse().unwrap();
+        let relayed: Multiaddr = "/ip4/5.6.7.8/udp/9000/quic-v1/p2p/12D3KooWQ9kBn1y89W1ELUDAvfKcnwJASMTFZsYsh2a84yrjMHqy/p2p-circuit".parse().unwrap();
+        let ws: Multiaddr = "/dns4/example.org/tcp/443/wss".parse().unwrap();
+        let mut addrs = vec![relayed.clone(), ws.clone(), tcp.clone(), quic.clone()];
+        sort_by_dial_preference(&mut addrs);
+        assert_eq!(addrs, vec![quic.clone(), tcp.clone(), ws, relayed]);
 
-        assert!(addr_is_reachable(&multiaddr!(
-            Ip4([192, 168, 0, 1]),
-            Tcp(12345u16)
-        )));
-        assert!(addr_is_reachable(&multiaddr!(
-            Ip4([10, 0, 0, 1]),
-            Tcp(12345u16)
-        )));
-        assert!(addr_is_reachable(&multiaddr!(
-            Ip4([172, 16, 0, 1]),
-            Tcp(12345u16)
-        )));
+        assert_eq!(quic_to_tcp_addr(&quic), Some(tcp.clone()));
+        assert_eq!(quic_to_tcp_addr(&tcp), None);
     }
 }
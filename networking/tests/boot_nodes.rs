@@ -0,0 +1,213 @@
+use std::{collections::HashMap, net::Ipv4Addr, time::Duration};
+
+use hickory_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::{rdata::TXT, RData, Record},
+    },
+};
+use libp2p::{Multiaddr, PeerId};
+use networking::{
+    boot_nodes::{BootNodeResolver, BootNodeSource},
+    cli::BootNode,
+};
+use tokio::net::UdpSocket;
+
+/// Answer TXT queries from `records`, keyed by domain name without the trailing dot.
+async fn stub_dns_server(records: HashMap<String, Vec<String>>) -> u16 {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let port = socket.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+            let Ok(request) = Message::from_vec(&buf[..len]) else {
+                continue;
+            };
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(request.op_code())
+                .set_recursion_desired(request.recursion_desired())
+                .set_recursion_available(true);
+            for query in request.queries() {
+                response.add_query(query.clone());
+                let name = query.name().to_ascii();
+                match records.get(name.trim_end_matches('.')) {
+                    Some(entries) => {
+                        for entry in entries {
+                            let txt = RData::TXT(TXT::new(vec![entry.clone()]));
+                            response.add_answer(Record::from_rdata(query.name().clone(), 60, txt));
+                        }
+                    }
+                    None => {
+                        response.set_response_code(ResponseCode::NXDomain);
+                    }
+                }
+            }
+            socket
+                .send_to(&response.to_vec().unwrap(), src)
+                .await
+                .unwrap();
+        }
+    });
+    port
+}
+
+fn resolver(port: u16, sources: &[&str], file: Option<std::path::PathBuf>) -> BootNodeResolver {
+    let name_servers =
+        NameServerConfigGroup::from_ips_clear(&[Ipv4Addr::LOCALHOST.into()], port, true);
+    let mut opts = ResolverOpts::default();
+    opts.timeout = Duration::from_secs(1);
+    opts.attempts = 1;
+    opts.use_hosts_file = false;
+    BootNodeResolver::with_config(
+        sources.iter().map(|s| s.parse().unwrap()).collect(),
+        file,
+        ResolverConfig::from_parts(None, vec![], name_servers),
+        opts,
+    )
+}
+
+fn node(peer_id: PeerId, address: &str) -> (PeerId, Multiaddr) {
+    (peer_id, address.parse().unwrap())
+}
+
+fn sorted(boot_nodes: Vec<BootNode>) -> Vec<(PeerId, Multiaddr)> {
+    let mut nodes: Vec<_> = boot_nodes
+        .into_iter()
+        .map(|n| (n.peer_id, n.address))
+        .collect();
+    nodes.sort_by_key(|(peer_id, addr)| (peer_id.to_string(), addr.to_string()));
+    nodes
+}
+
+#[test]
+fn test_parse_boot_nodes() {
+    let peer_id = PeerId::random();
+    let expected = node(peer_id, "/ip4/1.2.3.4/udp/1/quic-v1");
+    for s in [
+        format!("{peer_id} /ip4/1.2.3.4/udp/1/quic-v1"),
+        format!("/ip4/1.2.3.4/udp/1/quic-v1/p2p/{peer_id}"),
+    ] {
+        let node: BootNode = s.parse().unwrap();
+        assert_eq!((node.peer_id, node.address), expected);
+    }
+    assert!("/ip4/1.2.3.4/udp/1/quic-v1".parse::<BootNode>().is_err());
+    assert!(matches!(
+        "/dnsaddr/bootstrap.test".parse::<BootNodeSource>(),
+        Ok(BootNodeSource::DnsAddr(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_resolve_dnsaddr() {
+    let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+    let port = stub_dns_server(HashMap::from([
+        (
+            "_dnsaddr.bootstrap.test".to_string(),
+            vec![
+                format!("dnsaddr=/ip4/1.2.3.4/udp/1/quic-v1/p2p/{a}"),
+                "dnsaddr=/dnsaddr/eu.bootstrap.test".to_string(),
+                "unrelated record".to_string(),
+            ],
+        ),
+        (
+            "_dnsaddr.eu.bootstrap.test".to_string(),
+            vec![
+                format!("dnsaddr=/ip4/5.6.7.8/tcp/1/p2p/{b}"),
+                format!("dnsaddr=/ip4/5.6.7.9/tcp/1/p2p/{c}"),
+            ],
+        ),
+    ]))
+    .await;
+
+    let all = resolver(port, &["/dnsaddr/bootstrap.test"], None);
+    assert!(all.is_dynamic());
+    let mut expected = vec![
+        node(a, "/ip4/1.2.3.4/udp/1/quic-v1"),
+        node(b, "/ip4/5.6.7.8/tcp/1"),
+        node(c, "/ip4/5.6.7.9/tcp/1"),
+    ];
+    expected.sort_by_key(|(peer_id, addr)| (peer_id.to_string(), addr.to_string()));
+    assert_eq!(sorted(all.resolve().await), expected);
+
+    // A trailing `/p2p` picks a single node
+    let single = resolver(port, &[&format!("/dnsaddr/bootstrap.test/p2p/{b}")], None);
+    assert_eq!(
+        sorted(single.resolve().await),
+        [node(b, "/ip4/5.6.7.8/tcp/1")]
+    );
+
+    // Names which don't resolve are skipped
+    let peer_id = PeerId::random();
+    let missing = resolver(
+        port,
+        &[
+            "/dnsaddr/missing.test",
+            &format!("/ip4/9.9.9.9/tcp/1/p2p/{peer_id}"),
+        ],
+        None,
+    );
+    let resolved = missing.resolve_all().await;
+    assert!(!resolved.complete);
+    assert_eq!(
+        sorted(resolved.boot_nodes),
+        [node(peer_id, "/ip4/9.9.9.9/tcp/1")]
+    );
+    assert!(all.resolve_all().await.complete);
+
+    // Static boot nodes don't need to be resolved again, nor a DNS resolver
+    assert!(!resolver(port, &[&format!("/ip4/9.9.9.9/tcp/1/p2p/{peer_id}")], None).is_dynamic());
+    let static_only = BootNodeResolver::new(
+        vec![format!("/ip4/9.9.9.9/tcp/1/p2p/{peer_id}").parse().unwrap()],
+        None,
+    )
+    .unwrap();
+    assert_eq!(
+        sorted(static_only.resolve().await),
+        [node(peer_id, "/ip4/9.9.9.9/tcp/1")]
+    );
+}
+
+#[tokio::test]
+async fn test_boot_nodes_file() {
+    let (a, b) = (PeerId::random(), PeerId::random());
+    let port = stub_dns_server(HashMap::from([(
+        "_dnsaddr.bootstrap.test".to_string(),
+        vec![format!("dnsaddr=/ip4/5.6.7.8/tcp/1/p2p/{b}")],
+    )]))
+    .await;
+    let path = std::env::temp_dir().join(format!("boot-nodes-{}", rand::random::<u64>()));
+    std::fs::write(
+        &path,
+        format!(
+            "# Boot nodes\n\
+            {a} /ip4/1.2.3.4/udp/1/quic-v1\n\
+            \n\
+            /ip4/1.2.3.4/udp/1/quic-v1/p2p/{a}\n\
+            /dnsaddr/bootstrap.test\n"
+        ),
+    )
+    .unwrap();
+
+    let resolver = resolver(port, &[], Some(path.clone()));
+    assert!(resolver.is_dynamic());
+    let mut expected = vec![
+        node(a, "/ip4/1.2.3.4/udp/1/quic-v1"),
+        node(b, "/ip4/5.6.7.8/tcp/1"),
+    ];
+    expected.sort_by_key(|(peer_id, addr)| (peer_id.to_string(), addr.to_string()));
+    assert_eq!(sorted(resolver.resolve().await), expected);
+
+    // The file is read again on every refresh
+    std::fs::write(&path, format!("{a} /ip4/1.2.3.4/udp/1/quic-v1\n")).unwrap();
+    assert_eq!(
+        sorted(resolver.resolve().await),
+        [node(a, "/ip4/1.2.3.4/udp/1/quic-v1")]
+    );
+
+    std::fs::remove_file(path).unwrap();
+}
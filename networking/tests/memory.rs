@@ -0,0 +1,873 @@
+use std::{collections::HashSet, time::Duration};
+
+use futures::{FutureExt, StreamExt};
+use libp2p::{
+    identity::Keypair,
+    kad::Quorum,
+    multiaddr::Protocol,
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        DialError, SwarmEvent,
+    },
+    Multiaddr, PeerId, Swarm,
+};
+use networking::{
+    behaviour::{
+        base::{
+            BaseBehaviour, BaseBehaviourEvent, BaseConfig, ConnectionType, PeerProbed,
+            ProbeErrorKind, ProbeResult, TryProbeError,
+        },
+        relay_server::RelayServerConfig,
+        wrapped::Wrapped,
+    },
+    builder::P2PTransportBuilder,
+    chain_client::ContractClient,
+    cli::BootNode,
+    get_agent_info,
+    protocol::{Network, BLOCKS_TOPIC},
+    AgentInfo,
+};
+use tokio::sync::watch;
+
+type Node = Swarm<Wrapped<BaseBehaviour>>;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn build_node(keypair: Keypair, authorities: watch::Receiver<HashSet<PeerId>>) -> Node {
+    build_node_with(keypair, authorities, |builder| builder)
+}
+
+fn build_node_with(
+    keypair: Keypair,
+    authorities: watch::Receiver<HashSet<PeerId>>,
+    f: impl FnOnce(P2PTransportBuilder) -> P2PTransportBuilder,
+) -> Node {
+    let builder = P2PTransportBuilder::new(keypair, Network::Testnet, get_agent_info!())
+        .unwrap()
+        .with_memory_transport()
+        .with_listen_addrs(["/memory/0".parse().unwrap()])
+        .with_contract_client(ContractClient::with_authorities(authorities));
+    f(builder).build_default_swarm().unwrap()
+}
+
+/// Build a node in Kademlia server mode which bootstraps every 200ms and keeps its connections
+/// to the other authorities open.
+fn build_dht_node(
+    keypair: Keypair,
+    authorities: watch::Receiver<HashSet<PeerId>>,
+    boot_nodes: Vec<BootNode>,
+) -> Node {
+    build_node_with(keypair, authorities, |builder| {
+        builder
+            .with_boot_nodes(boot_nodes)
+            .with_base_config(|config| BaseConfig {
+                kad_server_mode: true,
+                kad_bootstrap_interval: Duration::from_millis(200),
+                kad_min_routing_table_size: 2,
+                maintain_authority_connections: true,
+                ..config
+            })
+    })
+}
+
+/// Keypairs of `n` nodes, all of them registered as authorities, with the sender and a
+/// receiver of the authority set.
+fn authority_keypairs(
+    n: usize,
+) -> (
+    impl Iterator<Item = Keypair>,
+    watch::Sender<HashSet<PeerId>>,
+    watch::Receiver<HashSet<PeerId>>,
+) {
+    let keypairs: Vec<_> = (0..n).map(|_| Keypair::generate_ed25519()).collect();
+    let (authorities, rx) =
+        watch::channel(keypairs.iter().map(|k| k.public().to_peer_id()).collect());
+    (keypairs.into_iter(), authorities, rx)
+}
+
+/// Build `n` nodes, all of them registered as authorities.
+fn build_authorities(n: usize) -> (Vec<Node>, watch::Sender<HashSet<PeerId>>) {
+    let (keypairs, authorities, rx) = authority_keypairs(n);
+    let nodes = keypairs.map(|k| build_node(k, rx.clone())).collect();
+    (nodes, authorities)
+}
+
+/// Let the node process everything that is ready, e.g. authority set updates.
+fn poll_once(swarm: &mut Node) {
+    let _ = swarm.select_next_some().now_or_never();
+}
+
+/// Wait for the node to start listening.
+async fn listen_addr(swarm: &mut Node) -> Multiaddr {
+    loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+            return address;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_probe_direct() {
+    let (mut nodes, _authorities) = build_authorities(2);
+    let mut target = nodes.pop().unwrap();
+    let mut prober = nodes.pop().unwrap();
+    let target_id = *target.local_peer_id();
+    let target_addr = listen_addr(&mut target).await;
+    listen_addr(&mut prober).await;
+
+    prober
+        .behaviour_mut()
+        .try_probe_direct(target_id, [target_addr.clone()])
+        .unwrap();
+
+    let probed = async {
+        loop {
+            tokio::select! {
+                _ = target.select_next_some() => {}
+                ev = prober.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BaseBehaviourEvent::PeerProbed(probed)) = ev {
+                        return probed;
+                    }
+                }
+            }
+        }
+    };
+    let PeerProbed { peer_id, result } = tokio::time::timeout(TIMEOUT, probed).await.unwrap();
+    assert_eq!(peer_id, target_id);
+    let ProbeResult::Reachable {
+        listen_addrs,
+        protocols,
+        addr,
+        connection_type,
+        ..
+    } = result
+    else {
+        panic!("Unexpected probe result: {result:?}");
+    };
+    assert!(listen_addrs.contains(&target_addr));
+    assert_eq!(addr, target_addr.with_p2p(target_id).unwrap());
+    assert_eq!(connection_type, ConnectionType::Direct);
+    assert!(protocols.iter().any(|p| p.as_ref() == "/ipfs/id/1.0.0"));
+}
+
+#[tokio::test]
+async fn test_probe_handle() {
+    let (mut nodes, _authorities) = build_authorities(2);
+    let mut target = nodes.pop().unwrap();
+    let mut prober = nodes.pop().unwrap();
+    let target_id = *target.local_peer_id();
+    let target_addr = listen_addr(&mut target).await;
+    listen_addr(&mut prober).await;
+
+    // Nothing listens on the first address, the probe succeeds on the second one
+    let unused_addr: Multiaddr = "/memory/1".parse().unwrap();
+    let mut handle = prober
+        .behaviour_mut()
+        .try_probe_direct(target_id, [unused_addr, target_addr.clone()])
+        .unwrap();
+    assert!(matches!(
+        prober.behaviour_mut().try_probe_dht(target_id),
+        Err(TryProbeError::Ongoing)
+    ));
+    let result = async {
+        loop {
+            tokio::select! {
+                _ = target.select_next_some() => {}
+                _ = prober.select_next_some() => {}
+                result = &mut handle => return result,
+            }
+        }
+    };
+    let result = tokio::time::timeout(TIMEOUT, result).await.unwrap();
+    let ProbeResult::Reachable { addr, .. } = result else {
+        panic!("Unexpected probe result: {result:?}");
+    };
+    assert_eq!(addr, target_addr.with_p2p(target_id).unwrap());
+
+    let peer_id = PeerId::random();
+    let handle = prober.behaviour_mut().try_probe_dht(peer_id).unwrap();
+    assert!(prober.behaviour_mut().cancel_probe(peer_id));
+    assert!(!prober.behaviour().is_probing(&peer_id));
+    assert!(matches!(
+        handle.await,
+        ProbeResult::Error {
+            kind: ProbeErrorKind::Cancelled,
+            ..
+        }
+    ));
+    assert!(!prober.behaviour_mut().cancel_probe(peer_id));
+}
+
+#[tokio::test]
+async fn test_pubsub_between_authorities() {
+    let (mut nodes, _authorities) = build_authorities(2);
+    let mut receiver = nodes.pop().unwrap();
+    let mut publisher = nodes.pop().unwrap();
+    let publisher_id = *publisher.local_peer_id();
+    receiver.behaviour_mut().subscribe(BLOCKS_TOPIC);
+    publisher.behaviour_mut().subscribe(BLOCKS_TOPIC);
+
+    let addr = listen_addr(&mut receiver).await;
+    listen_addr(&mut publisher).await;
+    publisher
+        .dial(addr.with(Protocol::P2p(*receiver.local_peer_id())))
+        .unwrap();
+
+    // Publish until the subscription has propagated and the message gets through
+    let mut publish_interval = tokio::time::interval(Duration::from_millis(200));
+    let received = async {
+        loop {
+            tokio::select! {
+                _ = publish_interval.tick() => {
+                    publisher.behaviour_mut().publish_message(BLOCKS_TOPIC, 42u64);
+                }
+                _ = publisher.select_next_some() => {}
+                ev = receiver.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BaseBehaviourEvent::Gossipsub(msg)) = ev {
+                        return msg;
+                    }
+                }
+            }
+        }
+    };
+    let msg = tokio::time::timeout(TIMEOUT, received).await.unwrap();
+    assert_eq!(msg.peer_id, publisher_id);
+    assert_eq!(msg.topic, BLOCKS_TOPIC);
+}
+
+#[tokio::test]
+async fn test_whitelist_update() {
+    let (mut nodes, _authorities) = build_authorities(1);
+    let mut authority = nodes.pop().unwrap();
+    let authority_id = *authority.local_peer_id();
+    // The outsider only trusts itself, so the authority is not whitelisted
+    let outsider_key = Keypair::generate_ed25519();
+    let outsider_id = outsider_key.public().to_peer_id();
+    let (outsider_authorities, rx) = watch::channel(HashSet::from([outsider_id]));
+    let mut outsider = build_node(outsider_key, rx);
+
+    let addr = listen_addr(&mut authority)
+        .await
+        .with(Protocol::P2p(authority_id));
+    listen_addr(&mut outsider).await;
+
+    let error = outsider.dial(addr.clone()).unwrap_err();
+    assert!(matches!(error, DialError::Denied { .. }), "{error:?}");
+
+    // Once the authority gets registered, it can be dialed
+    outsider_authorities.send_modify(|peers| {
+        peers.insert(authority_id);
+    });
+    poll_once(&mut outsider);
+    outsider.dial(addr.clone()).unwrap();
+
+    // ... and after it is removed, it's blocked again
+    outsider_authorities.send_modify(|peers| {
+        peers.remove(&authority_id);
+    });
+    poll_once(&mut outsider);
+    let error = outsider.dial(addr).unwrap_err();
+    assert!(matches!(error, DialError::Denied { .. }), "{error:?}");
+}
+
+#[tokio::test]
+async fn test_relay_server_reservations() {
+    let relay_key = Keypair::generate_ed25519();
+    let authority_key = Keypair::generate_ed25519();
+    let outsider_key = Keypair::generate_ed25519();
+    let relay_id = relay_key.public().to_peer_id();
+    let outsider_id = outsider_key.public().to_peer_id();
+    let (_authorities, rx) = watch::channel(HashSet::from([
+        relay_id,
+        authority_key.public().to_peer_id(),
+    ]));
+    let mut relay = build_node_with(relay_key, rx.clone(), |builder| {
+        builder.with_relay_server(RelayServerConfig::default())
+    });
+    let mut authority = build_node(authority_key, rx.clone());
+    let mut outsider = build_node(outsider_key, rx);
+
+    let relay_addr = listen_addr(&mut relay).await;
+    relay.add_external_address(relay_addr.clone());
+    // The outsider may connect to the relay, but it's not an authority
+    relay.behaviour_mut().allow_peer(outsider_id);
+    listen_addr(&mut authority).await;
+    listen_addr(&mut outsider).await;
+
+    let circuit_addr = relay_addr
+        .with(Protocol::P2p(relay_id))
+        .with(Protocol::P2pCircuit);
+    authority.listen_on(circuit_addr.clone()).unwrap();
+    let outsider_listener = outsider.listen_on(circuit_addr).unwrap();
+
+    let (mut reserved, mut denied) = (false, false);
+    let reservations = async {
+        while !(reserved && denied) {
+            tokio::select! {
+                _ = relay.select_next_some() => {}
+                ev = authority.select_next_some() => match ev {
+                    SwarmEvent::NewListenAddr { address, .. } => {
+                        reserved |= address.iter().any(|p| p == Protocol::P2pCircuit);
+                    }
+                    SwarmEvent::ListenerClosed { reason, .. } => {
+                        panic!("Authority reservation failed: {reason:?}")
+                    }
+                    _ => {}
+                },
+                ev = outsider.select_next_some() => match ev {
+                    SwarmEvent::NewListenAddr { address, .. }
+                        if address.iter().any(|p| p == Protocol::P2pCircuit) =>
+                    {
+                        panic!("Reservation for non-authority accepted")
+                    }
+                    SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                        assert_eq!(listener_id, outsider_listener);
+                        assert!(reason.is_err());
+                        denied = true;
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, reservations).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_dht_bootstrap() {
+    let (mut keypairs, _authorities, rx) = authority_keypairs(3);
+    let mut boot = build_dht_node(keypairs.next().unwrap(), rx.clone(), vec![]);
+    let boot_node = BootNode {
+        peer_id: *boot.local_peer_id(),
+        address: listen_addr(&mut boot).await,
+    };
+    let mut first = build_dht_node(
+        keypairs.next().unwrap(),
+        rx.clone(),
+        vec![boot_node.clone()],
+    );
+    let mut second = build_dht_node(keypairs.next().unwrap(), rx.clone(), vec![boot_node]);
+
+    // Both nodes only know the boot node, so they have to find each other via the DHT
+    let (mut first_size, mut second_size) = (None, None);
+    let bootstrapped = async {
+        while first_size.is_none() || second_size.is_none() {
+            tokio::select! {
+                _ = boot.select_next_some() => {}
+                ev = first.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BaseBehaviourEvent::DhtBootstrapped {
+                        routing_table_size,
+                    }) = ev
+                    {
+                        first_size = Some(routing_table_size);
+                    }
+                }
+                ev = second.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BaseBehaviourEvent::DhtBootstrapped {
+                        routing_table_size,
+                    }) = ev
+                    {
+                        second_size = Some(routing_table_size);
+                    }
+                }
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, bootstrapped).await.unwrap();
+    assert_eq!(first_size, Some(2));
+    assert_eq!(second_size, Some(2));
+}
+
+/// Drive the nodes until both `first` and `second` have bootstrapped the DHT.
+async fn bootstrap_dht(boot: &mut Node, first: &mut Node, second: &mut Node) {
+    let (mut first_done, mut second_done) = (false, false);
+    while !first_done || !second_done {
+        tokio::select! {
+            _ = boot.select_next_some() => {}
+            ev = first.select_next_some() => {
+                first_done |= matches!(
+                    ev,
+                    SwarmEvent::Behaviour(BaseBehaviourEvent::DhtBootstrapped { .. })
+                );
+            }
+            ev = second.select_next_some() => {
+                second_done |= matches!(
+                    ev,
+                    SwarmEvent::Behaviour(BaseBehaviourEvent::DhtBootstrapped { .. })
+                );
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_dht_records() {
+    let (mut keypairs, _authorities, rx) = authority_keypairs(3);
+    let mut boot = build_dht_node(keypairs.next().unwrap(), rx.clone(), vec![]);
+    let boot_node = BootNode {
+        peer_id: *boot.local_peer_id(),
+        address: listen_addr(&mut boot).await,
+    };
+    let mut publisher = build_dht_node(
+        keypairs.next().unwrap(),
+        rx.clone(),
+        vec![boot_node.clone()],
+    );
+    let mut reader = build_dht_node(keypairs.next().unwrap(), rx, vec![boot_node]);
+    let publisher_id = *publisher.local_peer_id();
+    tokio::time::timeout(
+        TIMEOUT,
+        bootstrap_dht(&mut boot, &mut publisher, &mut reader),
+    )
+    .await
+    .unwrap();
+
+    let put_query = publisher
+        .behaviour_mut()
+        .put_record(b"checkpoint", 42u64, Quorum::One)
+        .unwrap();
+    let stored = async {
+        loop {
+            tokio::select! {
+                _ = boot.select_next_some() => {}
+                _ = reader.select_next_some() => {}
+                ev = publisher.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BaseBehaviourEvent::RecordStored {
+                        query_id,
+                        result,
+                        ..
+                    }) = ev
+                    {
+                        assert_eq!(query_id, put_query);
+                        return result;
+                    }
+                }
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, stored)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let get_query = reader
+        .behaviour_mut()
+        .get_record(b"checkpoint", Quorum::One);
+    let found = async {
+        loop {
+            tokio::select! {
+                _ = boot.select_next_some() => {}
+                _ = publisher.select_next_some() => {}
+                ev = reader.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BaseBehaviourEvent::RecordFound {
+                        query_id,
+                        result,
+                        ..
+                    }) = ev
+                    {
+                        assert_eq!(query_id, get_query);
+                        return result;
+                    }
+                }
+            }
+        }
+    };
+    let record = tokio::time::timeout(TIMEOUT, found).await.unwrap().unwrap();
+    assert_eq!(record.publisher, publisher_id);
+    assert_eq!(record.decode::<u64>().unwrap(), 42);
+}
+
+#[tokio::test]
+async fn test_dht_providers() {
+    let (mut keypairs, _authorities, rx) = authority_keypairs(3);
+    let mut boot = build_dht_node(keypairs.next().unwrap(), rx.clone(), vec![]);
+    let boot_node = BootNode {
+        peer_id: *boot.local_peer_id(),
+        address: listen_addr(&mut boot).await,
+    };
+    let mut provider = build_dht_node(
+        keypairs.next().unwrap(),
+        rx.clone(),
+        vec![boot_node.clone()],
+    );
+    let mut seeker = build_dht_node(keypairs.next().unwrap(), rx, vec![boot_node]);
+    let provider_id = *provider.local_peer_id();
+    tokio::time::timeout(
+        TIMEOUT,
+        bootstrap_dht(&mut boot, &mut provider, &mut seeker),
+    )
+    .await
+    .unwrap();
+
+    provider
+        .behaviour_mut()
+        .start_providing(b"block-42")
+        .unwrap();
+    let started = async {
+        loop {
+            tokio::select! {
+                _ = boot.select_next_some() => {}
+                _ = seeker.select_next_some() => {}
+                ev = provider.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BaseBehaviourEvent::ProvidingStarted {
+                        result,
+                        ..
+                    }) = ev
+                    {
+                        return result;
+                    }
+                }
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, started)
+        .await
+        .unwrap()
+        .unwrap();
+
+    seeker.behaviour_mut().get_providers(b"block-42");
+    let mut dial = None;
+    let connected = async {
+        loop {
+            tokio::select! {
+                _ = boot.select_next_some() => {}
+                _ = provider.select_next_some() => {}
+                ev = seeker.select_next_some() => match ev {
+                    SwarmEvent::Behaviour(BaseBehaviourEvent::ProvidersFound {
+                        result, ..
+                    }) => {
+                        assert!(result.unwrap().contains(&provider_id));
+                        // The provider's address is cached, dialing by peer ID is enough
+                        let opts = DialOpts::peer_id(provider_id)
+                            .condition(PeerCondition::Always)
+                            .build();
+                        dial = Some(opts.connection_id());
+                        seeker.dial(opts).unwrap();
+                    }
+                    SwarmEvent::ConnectionEstablished { connection_id, .. }
+                        if Some(connection_id) == dial =>
+                    {
+                        return;
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, connected).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_authority_connections() {
+    let (mut keypairs, _authorities, rx) = authority_keypairs(2);
+    let maintain_connections = |config| BaseConfig {
+        maintain_authority_connections: true,
+        ..config
+    };
+    // Both sides keep the connection alive, like all authorities would
+    let mut remote = build_node_with(keypairs.next().unwrap(), rx.clone(), |builder| {
+        builder.with_base_config(maintain_connections)
+    });
+    let boot_node = BootNode {
+        peer_id: *remote.local_peer_id(),
+        address: listen_addr(&mut remote).await,
+    };
+    let mut node = build_node_with(keypairs.next().unwrap(), rx, |builder| {
+        builder
+            .with_boot_nodes([boot_node.clone()])
+            .with_base_config(maintain_connections)
+    });
+
+    let connected = async {
+        loop {
+            tokio::select! {
+                _ = remote.select_next_some() => {}
+                ev = node.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BaseBehaviourEvent::AuthorityConnectivity {
+                        connected,
+                        total,
+                    }) = ev
+                    {
+                        assert_eq!(total, 1);
+                        if connected == 1 {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, connected).await.unwrap();
+    assert_eq!(node.behaviour().authority_connectivity(), Some((1, 1)));
+
+    // The connection is kept open even though it's idle
+    let idle = async {
+        loop {
+            tokio::select! {
+                _ = remote.select_next_some() => {}
+                _ = node.select_next_some() => {}
+            }
+        }
+    };
+    let _ = tokio::time::timeout(Duration::from_secs(1), idle).await;
+    assert!(node.is_connected(&boot_node.peer_id));
+}
+
+#[tokio::test]
+async fn test_reachability_monitor() {
+    let (mut keypairs, authorities, rx) = authority_keypairs(3);
+    let offline = PeerId::random();
+    authorities.send_modify(|peers| {
+        peers.insert(offline);
+    });
+    let mut boot = build_dht_node(keypairs.next().unwrap(), rx.clone(), vec![]);
+    let boot_node = BootNode {
+        peer_id: *boot.local_peer_id(),
+        address: listen_addr(&mut boot).await,
+    };
+    let mut other = build_dht_node(
+        keypairs.next().unwrap(),
+        rx.clone(),
+        vec![boot_node.clone()],
+    );
+    let other_id = *other.local_peer_id();
+    let mut monitor = build_node_with(keypairs.next().unwrap(), rx, |builder| {
+        builder
+            .with_boot_nodes([boot_node.clone()])
+            .with_base_config(|config| BaseConfig {
+                kad_server_mode: true,
+                kad_bootstrap_interval: Duration::from_millis(200),
+                probe_timeout: Duration::from_secs(1),
+                reachability_interval: Some(Duration::from_millis(500)),
+                ..config
+            })
+    });
+
+    // The other authority may not be found in the first round, before the DHT is bootstrapped
+    let mut changes = Vec::new();
+    let round = async {
+        loop {
+            tokio::select! {
+                _ = boot.select_next_some() => {}
+                _ = other.select_next_some() => {}
+                ev = monitor.select_next_some() => match ev {
+                    SwarmEvent::Behaviour(BaseBehaviourEvent::ReachabilityChanged {
+                        peer_id,
+                        reachable,
+                    }) => changes.push((peer_id, reachable)),
+                    SwarmEvent::Behaviour(BaseBehaviourEvent::ReachabilityUpdated(matrix))
+                        if matrix.reachable_count() == 2 =>
+                    {
+                        return matrix
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+    let matrix = tokio::time::timeout(3 * TIMEOUT, round).await.unwrap();
+    assert_eq!(matrix.authorities.len(), 3);
+    assert_eq!(matrix.unreachable(), [offline]);
+    let reached = &matrix.authorities[&other_id];
+    assert!(reached.elapsed.is_some());
+    assert!(reached.agent_version.is_some());
+    assert!(!reached.listen_addrs.is_empty());
+    assert!(changes.contains(&(offline, false)));
+    assert!(changes.contains(&(other_id, true)));
+    assert_eq!(
+        monitor
+            .behaviour()
+            .reachability()
+            .unwrap()
+            .reachable_count(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn test_reachability_probes_keep_connections() {
+    let (mut keypairs, _authorities, rx) = authority_keypairs(2);
+    let mut target = build_dht_node(keypairs.next().unwrap(), rx.clone(), vec![]);
+    let target_id = *target.local_peer_id();
+    let boot_node = BootNode {
+        peer_id: target_id,
+        address: listen_addr(&mut target).await,
+    };
+    let mut monitor = build_node_with(keypairs.next().unwrap(), rx, |builder| {
+        builder
+            .with_boot_nodes([boot_node])
+            .with_base_config(|config| BaseConfig {
+                maintain_authority_connections: true,
+                reachability_interval: Some(Duration::from_millis(300)),
+                ..config
+            })
+    });
+
+    // Probes open connections of their own, the authority connection stays open
+    let (mut first_conn, mut rounds, mut closed) = (None, 0, Vec::new());
+    let probed = async {
+        while rounds < 3 {
+            tokio::select! {
+                _ = target.select_next_some() => {}
+                ev = monitor.select_next_some() => match ev {
+                    SwarmEvent::ConnectionEstablished { connection_id, .. } => {
+                        first_conn.get_or_insert(connection_id);
+                    }
+                    SwarmEvent::ConnectionClosed { connection_id, .. } => {
+                        closed.push(connection_id)
+                    }
+                    SwarmEvent::Behaviour(BaseBehaviourEvent::ReachabilityUpdated(matrix)) => {
+                        assert_eq!(matrix.reachable_count(), 1);
+                        rounds += 1;
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, probed).await.unwrap();
+    let first_conn = first_conn.unwrap();
+    assert!(!closed.contains(&first_conn));
+    // The probe connections are closed again
+    assert!(!closed.is_empty());
+    assert!(monitor.is_connected(&target_id));
+}
+
+#[tokio::test]
+async fn test_peer_latency() {
+    let (mut keypairs, _authorities, rx) = authority_keypairs(2);
+    let config = |config| BaseConfig {
+        maintain_authority_connections: true,
+        ping_interval: Duration::from_millis(100),
+        ..config
+    };
+    let mut remote = build_node_with(keypairs.next().unwrap(), rx.clone(), |builder| {
+        builder.with_base_config(config)
+    });
+    let boot_node = BootNode {
+        peer_id: *remote.local_peer_id(),
+        address: listen_addr(&mut remote).await,
+    };
+    let mut node = build_node_with(keypairs.next().unwrap(), rx, |builder| {
+        builder
+            .with_boot_nodes([boot_node.clone()])
+            .with_base_config(config)
+    });
+
+    let pinged = async {
+        loop {
+            tokio::select! {
+                _ = remote.select_next_some() => {}
+                ev = node.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BaseBehaviourEvent::PeerLatency {
+                        peer_id,
+                        latency,
+                    }) = ev
+                    {
+                        if latency.rtt.is_some_and(|rtt| rtt.samples >= 2) {
+                            return (peer_id, latency);
+                        }
+                    }
+                }
+            }
+        }
+    };
+    let (peer_id, latency) = tokio::time::timeout(TIMEOUT, pinged).await.unwrap();
+    assert_eq!(peer_id, boot_node.peer_id);
+    assert_eq!(latency.consecutive_failures, 0);
+    assert_eq!(node.behaviour().peer_latency(&peer_id), Some(&latency));
+}
+
+#[tokio::test]
+async fn test_relayed_connection_replaced_by_direct() {
+    let (mut keypairs, _authorities, rx) = authority_keypairs(3);
+    let maintain_connections = |config| BaseConfig {
+        maintain_authority_connections: true,
+        ..config
+    };
+    let mut relay = build_node_with(keypairs.next().unwrap(), rx.clone(), |builder| {
+        builder.with_relay_server(RelayServerConfig::default())
+    });
+    let mut listener = build_node_with(keypairs.next().unwrap(), rx.clone(), |builder| {
+        builder.with_base_config(maintain_connections)
+    });
+    let mut dialer = build_node_with(keypairs.next().unwrap(), rx, |builder| {
+        builder.with_base_config(maintain_connections)
+    });
+    let relay_id = *relay.local_peer_id();
+    let listener_id = *listener.local_peer_id();
+
+    let relay_addr = listen_addr(&mut relay).await;
+    relay.add_external_address(relay_addr.clone());
+    let direct_addr = listen_addr(&mut listener).await;
+    let circuit_addr = relay_addr
+        .with(Protocol::P2p(relay_id))
+        .with(Protocol::P2pCircuit);
+    listener.listen_on(circuit_addr.clone()).unwrap();
+
+    let reserved = async {
+        loop {
+            tokio::select! {
+                _ = relay.select_next_some() => {}
+                ev = listener.select_next_some() => {
+                    if let SwarmEvent::NewListenAddr { address, .. } = ev {
+                        if address.iter().any(|p| p == Protocol::P2pCircuit) {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, reserved).await.unwrap();
+
+    poll_once(&mut dialer);
+    dialer
+        .dial(circuit_addr.with(Protocol::P2p(listener_id)))
+        .unwrap();
+    let relayed_conn = async {
+        loop {
+            tokio::select! {
+                _ = relay.select_next_some() => {}
+                _ = listener.select_next_some() => {}
+                ev = dialer.select_next_some() => {
+                    if let SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } = ev {
+                        if peer_id == listener_id && endpoint.is_relayed() {
+                            return connection_id;
+                        }
+                    }
+                }
+            }
+        }
+    };
+    let relayed_conn = tokio::time::timeout(TIMEOUT, relayed_conn).await.unwrap();
+    assert_eq!(
+        dialer.behaviour().connection_type(&listener_id),
+        Some(ConnectionType::Relayed)
+    );
+
+    dialer
+        .dial(direct_addr.with(Protocol::P2p(listener_id)))
+        .unwrap();
+    let relayed_closed = async {
+        loop {
+            tokio::select! {
+                _ = relay.select_next_some() => {}
+                _ = listener.select_next_some() => {}
+                ev = dialer.select_next_some() => {
+                    if let SwarmEvent::ConnectionClosed { connection_id, cause, .. } = ev {
+                        if connection_id == relayed_conn {
+                            assert!(cause.is_none(), "Relayed connection failed: {cause:?}");
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, relayed_closed).await.unwrap();
+    assert_eq!(
+        dialer.behaviour().connection_type(&listener_id),
+        Some(ConnectionType::Direct)
+    );
+}
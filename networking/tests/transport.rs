@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use libp2p::{
+    core::ConnectedPoint, identity::Keypair, multiaddr::Protocol, swarm::SwarmEvent, Multiaddr,
+    Swarm,
+};
+use networking::{
+    behaviour::{base::BaseBehaviour, wrapped::Wrapped},
+    builder::P2PTransportBuilder,
+    get_agent_info,
+    protocol::{Network, TransportKind},
+    AgentInfo,
+};
+
+fn build_node(transport: TransportKind, listen_addr: &str) -> Swarm<Wrapped<BaseBehaviour>> {
+    P2PTransportBuilder::new(
+        Keypair::generate_ed25519(),
+        Network::Testnet,
+        get_agent_info!(),
+    )
+    .unwrap()
+    .with_transports([transport])
+    .with_listen_addrs([listen_addr.parse().unwrap()])
+    .build_default_swarm()
+    .unwrap()
+}
+
+async fn listen_addr(swarm: &mut Swarm<Wrapped<BaseBehaviour>>, kind: TransportKind) -> Multiaddr {
+    loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+            if TransportKind::of(&address) == Some(kind) {
+                return address;
+            }
+        }
+    }
+}
+
+/// Connect the dialer to the listener over the given transport and return the address
+/// it connected to.
+async fn connect(
+    listener: &mut Swarm<Wrapped<BaseBehaviour>>,
+    dialer: &mut Swarm<Wrapped<BaseBehaviour>>,
+    kind: TransportKind,
+) -> Multiaddr {
+    let listener_id = *listener.local_peer_id();
+    let dialer_id = *dialer.local_peer_id();
+    listener.behaviour_mut().allow_peer(dialer_id);
+    dialer.behaviour_mut().allow_peer(listener_id);
+
+    let addr = listen_addr(listener, kind).await;
+    dialer.dial(addr.with(Protocol::P2p(listener_id))).unwrap();
+
+    let connected = async {
+        loop {
+            tokio::select! {
+                _ = listener.select_next_some() => {}
+                ev = dialer.select_next_some() => match ev {
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                        return (peer_id, endpoint);
+                    }
+                    SwarmEvent::OutgoingConnectionError { error, .. } => {
+                        panic!("Connection failed: {error}");
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+    let (peer_id, endpoint) = tokio::time::timeout(Duration::from_secs(10), connected)
+        .await
+        .expect("connection should be established");
+    assert_eq!(peer_id, listener_id);
+    let ConnectedPoint::Dialer { address, .. } = endpoint else {
+        panic!("Expected outbound connection");
+    };
+    address
+}
+
+#[tokio::test]
+async fn test_websocket_connection() {
+    let mut listener = build_node(TransportKind::Ws, "/ip4/127.0.0.1/tcp/0/ws");
+    let mut dialer = build_node(TransportKind::Ws, "/ip4/127.0.0.1/tcp/0/ws");
+    let address = connect(&mut listener, &mut dialer, TransportKind::Ws).await;
+    assert_eq!(TransportKind::of(&address), Some(TransportKind::Ws));
+}
+
+#[tokio::test]
+async fn test_tcp_connection() {
+    // The TCP listen address is derived from the QUIC one
+    let mut listener = build_node(TransportKind::Tcp, "/ip4/127.0.0.1/udp/0/quic-v1");
+    let mut dialer = build_node(TransportKind::Tcp, "/ip4/127.0.0.1/udp/0/quic-v1");
+    let address = connect(&mut listener, &mut dialer, TransportKind::Tcp).await;
+    assert_eq!(TransportKind::of(&address), Some(TransportKind::Tcp));
+}